@@ -0,0 +1,473 @@
+use crate::bpmdata::{get_bpm_from_name, get_bpm_name, BpmData, Ring, TimeAxis};
+use crate::timezone;
+use crate::units::{Calibration, Positions, Units};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+
+/// What was asked of the archiver for an acquisition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    /// The command sent to the archiver, without the trailing newline.
+    pub command: String,
+    /// The host and port of the archiver.
+    #[serde(default)]
+    pub archiver: String,
+}
+
+/// A stretch of the requested time range for which there is no data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gap {
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+}
+
+/// The data from one acquisition: the positions of a set of BPMs in one ring, all sampled at the
+/// same times.
+#[derive(Debug, Clone, Default)]
+pub struct Acquisition {
+    pub ring: Ring,
+    pub fs: f64,
+    /// How many archiver samples were combined into each sample, if it is known.  When it is
+    /// not, `fs` is the rate of the samples themselves.
+    pub decimation: Option<usize>,
+    /// The request that produced the data, if it is known.  Data read back from most formats
+    /// does not record it.
+    pub request: Option<Request>,
+    pub gaps: Vec<Gap>,
+    pub ts: TimeAxis,
+    pub bpms: Vec<BpmData>,
+    /// The units the positions are written in.  The positions themselves are stored in the
+    /// archiver's units, and also as they were read from files written in other units.
+    pub units: Units,
+    /// The calibration to apply to the positions when they are written.
+    pub calibration: Calibration,
+    /// Whether a calibration had already been applied to the positions in the file they were
+    /// read from.
+    pub calibrated: bool,
+    /// The metadata header of the file the data was read from, if it had one.
+    pub header: Vec<(String, String)>,
+}
+
+impl Acquisition {
+    /// Builds an acquisition from data without any record of how it was acquired.
+    ///
+    /// The sample rate is recovered from the time axis, and the decimation is left unknown.
+    pub fn new(ring: Ring, ts: TimeAxis, bpms: Vec<BpmData>) -> Self {
+        Self {
+            ring,
+            fs: sample_rate(&ts),
+            ts,
+            bpms,
+            ..Default::default()
+        }
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.ts.len
+    }
+
+    /// The times of the first and last samples.
+    pub fn time_range(&self) -> Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        match self.ts.len {
+            0 => None,
+            n => Some((self.ts.time(0), self.ts.time(n - 1))),
+        }
+    }
+
+    /// The start of the requested time range, or the time of the first sample if the request is
+    /// not known.
+    pub fn start_time(&self) -> DateTime<FixedOffset> {
+        match &self.request {
+            Some(request) => request.start,
+            None => self.ts.time(0),
+        }
+    }
+
+    pub fn bpm_name(&self, bpm: &BpmData) -> String {
+        get_bpm_name(bpm.bpmnum, &self.ring).unwrap()
+    }
+
+    pub fn bpm_names(&self) -> Vec<String> {
+        self.bpms.iter().map(|bpm| self.bpm_name(bpm)).collect()
+    }
+
+    /// The BPM at the given position in this acquisition.
+    pub fn bpm(&self, index: usize) -> Option<&BpmData> {
+        self.bpms.get(index)
+    }
+
+    pub fn bpm_by_name(&self, name: &str) -> Option<&BpmData> {
+        let (ring, bpmnum) = get_bpm_from_name(name)?;
+        if ring != self.ring {
+            return None;
+        }
+        self.bpms.iter().find(|bpm| bpm.bpmnum == bpmnum)
+    }
+
+    /// Finds the entry for a BPM, adding an empty one if there is none yet.
+    ///
+    /// The ring of an empty acquisition is taken from the first BPM added to it, and BPMs from
+    /// any other ring are refused.
+    pub fn get_or_insert_bpm(&mut self, ring: Ring, bpmnum: usize) -> Result<&mut BpmData> {
+        if self.bpms.is_empty() && self.ring == Ring::default() {
+            self.ring = ring.clone();
+        }
+        if ring != self.ring {
+            return Err(mixed_rings());
+        }
+        let pos = match self.bpms.iter().position(|x| x.bpmnum == bpmnum) {
+            Some(pos) => pos,
+            None => {
+                self.bpms.push(BpmData {
+                    bpmnum,
+                    ..Default::default()
+                });
+                self.bpms.len() - 1
+            }
+        };
+        Ok(&mut self.bpms[pos])
+    }
+
+    /// Sets the time axis, recovering the sample rate from it as in `new`.
+    pub fn set_time_axis(&mut self, ts: TimeAxis) {
+        self.fs = sample_rate(&ts);
+        self.decimation = None;
+        self.ts = ts;
+    }
+
+    /// The x and y positions of a BPM in the output units, with any calibration applied.
+    pub fn positions<'a>(&self, bpm: &'a BpmData) -> (Positions<'a>, Positions<'a>) {
+        let c = self.calibration.get(&self.bpm_name(bpm));
+        match &bpm.scaled {
+            Some(scaled) if self.units != Units::Raw => (
+                Positions::from_scaled(&scaled.x, scaled.units, self.units, c.x_gain, c.x_offset),
+                Positions::from_scaled(&scaled.y, scaled.units, self.units, c.y_gain, c.y_offset),
+            ),
+            _ => (
+                Positions::new(&bpm.x, self.units, c.x_gain, c.x_offset),
+                Positions::new(&bpm.y, self.units, c.y_gain, c.y_offset),
+            ),
+        }
+    }
+
+    /// A description of the acquisition, as `(key, value)` pairs, for the headers of text files.
+    pub fn metadata(&self) -> Vec<(&'static str, String)> {
+        let time = |t: &DateTime<FixedOffset>| t.to_rfc3339_opts(SecondsFormat::Nanos, false);
+        let mut metadata = vec![
+            ("fars_version", crate::VERSION_NUMBER.to_string()),
+            ("written", time(&timezone::now())),
+            ("host", hostname()),
+            ("ring", self.ring.to_string()),
+        ];
+        if let Some(request) = &self.request {
+            metadata.push(("requested_start", time(&request.start)));
+            metadata.push(("requested_end", time(&request.end)));
+        }
+        if let Some((first, last)) = self.time_range() {
+            metadata.push(("first_sample", time(&first)));
+            metadata.push(("last_sample", time(&last)));
+        }
+        metadata.push(("samples", self.num_samples().to_string()));
+        if let Some(decimation) = self.decimation {
+            metadata.push(("sample_rate_hz", self.fs.to_string()));
+            metadata.push(("decimation", self.decimation_mode(decimation)));
+        }
+        metadata.push(("sample_interval_ns", self.ts.period_ns.to_string()));
+        metadata.push((
+            "units",
+            match self.units.name() {
+                Some(units) => units.to_string(),
+                None => "nm (integers, as returned by the archiver)".to_string(),
+            },
+        ));
+        if self.calibrated || !self.calibration.is_empty() {
+            metadata.push(("calibration", "per-BPM gain and offset applied".to_string()));
+        }
+        if let Some(request) = &self.request {
+            if !request.archiver.is_empty() {
+                metadata.push(("archiver", request.archiver.clone()));
+            }
+            metadata.push(("command", request.command.clone()));
+        }
+        metadata.push(("gaps", self.gap_summary()));
+        metadata
+    }
+
+    /// Restores what a header written from `metadata` says about how the data was acquired:
+    /// the sample rate and decimation, the request, whether a calibration was applied and the
+    /// gaps.  Keys that are missing or that
+    /// cannot be understood are left alone.  The header itself is kept in `header`.
+    pub fn apply_metadata(&mut self, metadata: &[(String, String)]) {
+        self.header = metadata.to_vec();
+        let get = |key: &str| {
+            metadata
+                .iter()
+                .find(|(x, _)| x == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let time = |key: &str| get(key).and_then(|x| DateTime::parse_from_rfc3339(x).ok());
+
+        let fs = get("sample_rate_hz").and_then(|x| x.parse::<f64>().ok());
+        let decimation = get("decimation").and_then(|x| match x.split_once(' ') {
+            Some(("none", _)) => Some(1),
+            Some((decimation, _)) => decimation.parse().ok(),
+            None => x.parse().ok(),
+        });
+        if let (Some(fs), Some(decimation)) = (fs, decimation) {
+            self.fs = fs;
+            self.decimation = Some(decimation);
+        }
+
+        if let (Some(start), Some(end), Some(command)) = (
+            time("requested_start"),
+            time("requested_end"),
+            get("command"),
+        ) {
+            self.request = Some(Request {
+                start,
+                end,
+                command: command.to_string(),
+                archiver: get("archiver").unwrap_or_default().to_string(),
+            });
+        }
+
+        self.calibrated |= get("calibration").is_some();
+
+        if let Some(gaps) = get("gaps").filter(|x| *x != "none") {
+            self.gaps = gaps
+                .split("; ")
+                .filter_map(|gap| gap.split_once(" to "))
+                .filter_map(|(start, end)| {
+                    Some(Gap {
+                        start: DateTime::parse_from_rfc3339(start).ok()?,
+                        end: DateTime::parse_from_rfc3339(end).ok()?,
+                    })
+                })
+                .collect();
+        }
+    }
+
+    fn decimation_mode(&self, decimation: usize) -> String {
+        if decimation <= 1 {
+            return "none (full rate)".to_string();
+        }
+        // Decimated data is requested as `RD<fields>...`, where the fields are a bit mask.
+        let fields = self
+            .request
+            .as_ref()
+            .and_then(|request| request.command.strip_prefix("RDF"))
+            .map(|x| {
+                x.chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>()
+            })
+            .and_then(|x| x.parse::<u32>().ok())
+            .map(|mask| {
+                ["mean", "min", "max", "std"]
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+        match fields {
+            Some(fields) => format!("{} ({})", decimation, fields),
+            None => decimation.to_string(),
+        }
+    }
+
+    fn gap_summary(&self) -> String {
+        if self.gaps.is_empty() {
+            return "none".to_string();
+        }
+        self.gaps
+            .iter()
+            .map(|gap| {
+                format!(
+                    "{} to {}",
+                    gap.start.to_rfc3339_opts(SecondsFormat::Nanos, false),
+                    gap.end.to_rfc3339_opts(SecondsFormat::Nanos, false)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// Adds the BPMs of another acquisition of the same ring and times to this one.
+    pub fn merge(&mut self, other: Self) -> Result<()> {
+        if self.bpms.is_empty() {
+            *self = other;
+            return Ok(());
+        }
+        if other.ring != self.ring {
+            return Err(mixed_rings());
+        }
+        if other.calibrated != self.calibrated {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Calibrated and uncalibrated data cannot be combined",
+            ));
+        }
+        if other.ts != self.ts {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The data was not all sampled at the same times",
+            ));
+        }
+        for bpm in other.bpms {
+            let name = self.bpm_name(&bpm);
+            if self.bpm_by_name(&name).is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} was given more than once", name),
+                ));
+            }
+            self.bpms.push(bpm);
+        }
+        self.bpms.sort_by_key(|bpm| bpm.bpmnum);
+        Ok(())
+    }
+}
+
+/// Parses a `# key: value` line of the header of a text file.
+pub fn parse_header_line(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.strip_prefix("# ")?.split_once(": ")?;
+    Some((key.to_string(), value.to_string()))
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn sample_rate(ts: &TimeAxis) -> f64 {
+    if ts.period_ns > 0.0 {
+        1_000_000_000f64 / ts.period_ns
+    } else {
+        0.0
+    }
+}
+
+fn mixed_rings() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "All BPMs of an acquisition must come from the same ring",
+    )
+}
+
+/// Acquisitions for the round-trip tests of the readers and writers.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::compress::Compression;
+    use crate::timezone::{parse_timezone, set_timezone};
+
+    /// A path in the temporary directory for a test file, unique to this process.
+    pub fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("fars-test-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Two BPMs of R3 over four samples, as written with raw units, with `--units um`, and with
+    /// a calibration and `--units um`.
+    pub fn acquisitions() -> Vec<(&'static str, Acquisition)> {
+        set_timezone(Some(parse_timezone("Europe/Stockholm").unwrap()));
+        let ring = Ring::from_name("R3").unwrap();
+        let ts: Vec<i64> = (0..4)
+            .map(|i| 1_696_413_600_000_000_000 + i * 100_000)
+            .collect();
+        let bpms = [9, 10]
+            .into_iter()
+            .map(|bpmnum| BpmData {
+                bpmnum,
+                x: vec![15, -2_000_001, 0, i32::MAX],
+                y: vec![-15, 7, 1_234_567, i32::MIN],
+                scaled: None,
+            })
+            .collect();
+        let raw = Acquisition {
+            decimation: Some(1),
+            request: Some(Request {
+                start: DateTime::parse_from_rfc3339("2023-10-04T12:00:00+02:00").unwrap(),
+                end: DateTime::parse_from_rfc3339("2023-10-04T12:00:00.0004+02:00").unwrap(),
+                command: "RFM9,10S1696413600.000000000N4N".to_string(),
+                archiver: "fa:32001".to_string(),
+            }),
+            ..Acquisition::new(ring, TimeAxis::from_nanos(&ts), bpms)
+        };
+
+        // Tests run in parallel, so each thread needs its own calibration file.
+        let fname = temp_file(&format!("{:?}.cal", std::thread::current().id()));
+        std::fs::write(&fname, "R3-301M2/DIA/BPM-02 1.0153 0.7 0.9871 -3.3\n").unwrap();
+        let calibration = Calibration::read_from_file(&fname);
+        std::fs::remove_file(&fname).unwrap();
+        let calibrated = Acquisition {
+            units: Units::Um,
+            calibration: calibration.unwrap(),
+            ..raw.clone()
+        };
+        let scaled = Acquisition {
+            units: Units::Um,
+            ..raw.clone()
+        };
+        vec![("raw", raw), ("um", scaled), ("calibrated", calibrated)]
+    }
+
+    /// Writes an acquisition and reads it back, removing the file afterwards.
+    pub fn write_and_read(
+        acq: &Acquisition,
+        fname: &str,
+        write: impl FnOnce(&Acquisition, &str, &Compression) -> Result<()>,
+        read: impl FnOnce(&str) -> Result<Acquisition>,
+    ) -> Acquisition {
+        let fname = temp_file(fname);
+        write(acq, &fname, &Compression::None).unwrap();
+        let result = read(&fname);
+        std::fs::remove_file(&fname).unwrap();
+        result.unwrap()
+    }
+
+    /// Checks that an acquisition read back holds the data that was written, and would write the
+    /// same positions again.
+    pub fn assert_same_data(read: &Acquisition, written: &Acquisition) {
+        assert_eq!(read.ring, written.ring);
+        assert_eq!(read.ts, written.ts);
+        assert_eq!(read.units, written.units);
+        assert_eq!(read.calibrated, !written.calibration.is_empty());
+        assert!(read.calibration.is_empty());
+        assert_eq!(read.bpms.len(), written.bpms.len());
+        for (read_bpm, written_bpm) in read.bpms.iter().zip(&written.bpms) {
+            assert_eq!(read_bpm.bpmnum, written_bpm.bpmnum);
+            let positions = |acq: &Acquisition, bpm| {
+                let (x, y) = acq.positions(bpm);
+                (0..x.len())
+                    .map(|i| (x.get(i), y.get(i)))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                positions(read, read_bpm),
+                positions(written, written_bpm),
+                "{}",
+                read.bpm_name(read_bpm)
+            );
+        }
+    }
+
+    /// Checks that what a file header says about the acquisition was read back.
+    pub fn assert_same_metadata(read: &Acquisition, written: &Acquisition) {
+        assert_eq!(read.fs, written.fs);
+        assert_eq!(read.decimation, written.decimation);
+        assert_eq!(read.request, written.request);
+        assert_eq!(read.gaps, written.gaps);
+    }
+}
@@ -1,258 +1,25 @@
-use itertools::izip;
+use crate::acquisition::{parse_header_line, Acquisition};
+use crate::compress::{self, Compression};
+use crate::config::{ring_definition, ring_names};
+use crate::timespec::check_time_range;
+use crate::timezone::{from_local_in_file, from_nanos, from_utc};
+use crate::units::{ScaledPositions, Units};
+use crate::writer::OutputWriter;
+use chrono::prelude::*;
+use itertools::{izip, Itertools};
 use regex::Regex;
-use std::fmt::Write as fmt_wrt;
-use std::fs::File;
-use std::io::Write;
-
-const R3_BPMNAME_LIST: &[&str] = &[
-    "R3-301M1/DIA/BPM-01",
-    "R3-301M1/DIA/BPM-02",
-    "R3-301U1/DIA/BPM-01",
-    "R3-301U2/DIA/BPM-01",
-    "R3-301U3/DIA/BPM-01",
-    "R3-301U3/DIA/BPM-02",
-    "R3-301U4/DIA/BPM-01",
-    "R3-301U5/DIA/BPM-01",
-    "R3-301M2/DIA/BPM-01",
-    "R3-301M2/DIA/BPM-02",
-    "R3-302M1/DIA/BPM-01",
-    "R3-302M1/DIA/BPM-02",
-    "R3-302U1/DIA/BPM-01",
-    "R3-302U2/DIA/BPM-01",
-    "R3-302U3/DIA/BPM-01",
-    "R3-302U3/DIA/BPM-02",
-    "R3-302U4/DIA/BPM-01",
-    "R3-302U5/DIA/BPM-01",
-    "R3-302M2/DIA/BPM-01",
-    "R3-302M2/DIA/BPM-02",
-    "R3-303M1/DIA/BPM-01",
-    "R3-303M1/DIA/BPM-02",
-    "R3-303U1/DIA/BPM-01",
-    "R3-303U2/DIA/BPM-01",
-    "R3-303U3/DIA/BPM-01",
-    "R3-303U3/DIA/BPM-02",
-    "R3-303U4/DIA/BPM-01",
-    "R3-303U5/DIA/BPM-01",
-    "R3-303M2/DIA/BPM-01",
-    "R3-303M2/DIA/BPM-02",
-    "R3-304M1/DIA/BPM-01",
-    "R3-304M1/DIA/BPM-02",
-    "R3-304U1/DIA/BPM-01",
-    "R3-304U2/DIA/BPM-01",
-    "R3-304U3/DIA/BPM-01",
-    "R3-304U3/DIA/BPM-02",
-    "R3-304U4/DIA/BPM-01",
-    "R3-304U5/DIA/BPM-01",
-    "R3-304M2/DIA/BPM-01",
-    "R3-304M2/DIA/BPM-02",
-    "R3-305M1/DIA/BPM-01",
-    "R3-305M1/DIA/BPM-02",
-    "R3-305U1/DIA/BPM-01",
-    "R3-305U2/DIA/BPM-01",
-    "R3-305U3/DIA/BPM-01",
-    "R3-305U3/DIA/BPM-02",
-    "R3-305U4/DIA/BPM-01",
-    "R3-305U5/DIA/BPM-01",
-    "R3-305M2/DIA/BPM-01",
-    "R3-305M2/DIA/BPM-02",
-    "R3-306M1/DIA/BPM-01",
-    "R3-306M1/DIA/BPM-02",
-    "R3-306U1/DIA/BPM-01",
-    "R3-306U2/DIA/BPM-01",
-    "R3-306U3/DIA/BPM-01",
-    "R3-306U3/DIA/BPM-02",
-    "R3-306U4/DIA/BPM-01",
-    "R3-306U5/DIA/BPM-01",
-    "R3-306M2/DIA/BPM-01",
-    "R3-306M2/DIA/BPM-02",
-    "R3-307M1/DIA/BPM-01",
-    "R3-307M1/DIA/BPM-02",
-    "R3-307U1/DIA/BPM-01",
-    "R3-307U2/DIA/BPM-01",
-    "R3-307U3/DIA/BPM-01",
-    "R3-307U3/DIA/BPM-02",
-    "R3-307U4/DIA/BPM-01",
-    "R3-307U5/DIA/BPM-01",
-    "R3-307M2/DIA/BPM-01",
-    "R3-307M2/DIA/BPM-02",
-    "R3-308M1/DIA/BPM-01",
-    "R3-308M1/DIA/BPM-02",
-    "R3-308U1/DIA/BPM-01",
-    "R3-308U2/DIA/BPM-01",
-    "R3-308U3/DIA/BPM-01",
-    "R3-308U3/DIA/BPM-02",
-    "R3-308U4/DIA/BPM-01",
-    "R3-308U5/DIA/BPM-01",
-    "R3-308M2/DIA/BPM-01",
-    "R3-308M2/DIA/BPM-02",
-    "R3-309M1/DIA/BPM-01",
-    "R3-309M1/DIA/BPM-02",
-    "R3-309U1/DIA/BPM-01",
-    "R3-309U2/DIA/BPM-01",
-    "R3-309U3/DIA/BPM-01",
-    "R3-309U3/DIA/BPM-02",
-    "R3-309U4/DIA/BPM-01",
-    "R3-309U5/DIA/BPM-01",
-    "R3-309M2/DIA/BPM-01",
-    "R3-309M2/DIA/BPM-02",
-    "R3-310M1/DIA/BPM-01",
-    "R3-310M1/DIA/BPM-02",
-    "R3-310U1/DIA/BPM-01",
-    "R3-310U2/DIA/BPM-01",
-    "R3-310U3/DIA/BPM-01",
-    "R3-310U3/DIA/BPM-02",
-    "R3-310U4/DIA/BPM-01",
-    "R3-310U5/DIA/BPM-01",
-    "R3-310M2/DIA/BPM-01",
-    "R3-310M2/DIA/BPM-02",
-    "R3-311M1/DIA/BPM-01",
-    "R3-311M1/DIA/BPM-02",
-    "R3-311U1/DIA/BPM-01",
-    "R3-311U2/DIA/BPM-01",
-    "R3-311U3/DIA/BPM-01",
-    "R3-311U3/DIA/BPM-02",
-    "R3-311U4/DIA/BPM-01",
-    "R3-311U5/DIA/BPM-01",
-    "R3-311M2/DIA/BPM-01",
-    "R3-311M2/DIA/BPM-02",
-    "R3-312M1/DIA/BPM-01",
-    "R3-312M1/DIA/BPM-02",
-    "R3-312U1/DIA/BPM-01",
-    "R3-312U2/DIA/BPM-01",
-    "R3-312U3/DIA/BPM-01",
-    "R3-312U3/DIA/BPM-02",
-    "R3-312U4/DIA/BPM-01",
-    "R3-312U5/DIA/BPM-01",
-    "R3-312M2/DIA/BPM-01",
-    "R3-312M2/DIA/BPM-02",
-    "R3-313M1/DIA/BPM-01",
-    "R3-313M1/DIA/BPM-02",
-    "R3-313U1/DIA/BPM-01",
-    "R3-313U2/DIA/BPM-01",
-    "R3-313U3/DIA/BPM-01",
-    "R3-313U3/DIA/BPM-02",
-    "R3-313U4/DIA/BPM-01",
-    "R3-313U5/DIA/BPM-01",
-    "R3-313M2/DIA/BPM-01",
-    "R3-313M2/DIA/BPM-02",
-    "R3-314M1/DIA/BPM-01",
-    "R3-314M1/DIA/BPM-02",
-    "R3-314U1/DIA/BPM-01",
-    "R3-314U2/DIA/BPM-01",
-    "R3-314U3/DIA/BPM-01",
-    "R3-314U3/DIA/BPM-02",
-    "R3-314U4/DIA/BPM-01",
-    "R3-314U5/DIA/BPM-01",
-    "R3-314M2/DIA/BPM-01",
-    "R3-314M2/DIA/BPM-02",
-    "R3-315M1/DIA/BPM-01",
-    "R3-315M1/DIA/BPM-02",
-    "R3-315U1/DIA/BPM-01",
-    "R3-315U2/DIA/BPM-01",
-    "R3-315U3/DIA/BPM-01",
-    "R3-315U3/DIA/BPM-02",
-    "R3-315U4/DIA/BPM-01",
-    "R3-315U5/DIA/BPM-01",
-    "R3-315M2/DIA/BPM-01",
-    "R3-315M2/DIA/BPM-02",
-    "R3-316M1/DIA/BPM-01",
-    "R3-316M1/DIA/BPM-02",
-    "R3-316U1/DIA/BPM-01",
-    "R3-316U2/DIA/BPM-01",
-    "R3-316U3/DIA/BPM-01",
-    "R3-316U3/DIA/BPM-02",
-    "R3-316U4/DIA/BPM-01",
-    "R3-316U5/DIA/BPM-01",
-    "R3-316M2/DIA/BPM-01",
-    "R3-316M2/DIA/BPM-02",
-    "R3-317M1/DIA/BPM-01",
-    "R3-317M1/DIA/BPM-02",
-    "R3-317U1/DIA/BPM-01",
-    "R3-317U2/DIA/BPM-01",
-    "R3-317U3/DIA/BPM-01",
-    "R3-317U3/DIA/BPM-02",
-    "R3-317U4/DIA/BPM-01",
-    "R3-317U5/DIA/BPM-01",
-    "R3-317M2/DIA/BPM-01",
-    "R3-317M2/DIA/BPM-02",
-    "R3-318M1/DIA/BPM-01",
-    "R3-318M1/DIA/BPM-02",
-    "R3-318U1/DIA/BPM-01",
-    "R3-318U2/DIA/BPM-01",
-    "R3-318U3/DIA/BPM-01",
-    "R3-318U3/DIA/BPM-02",
-    "R3-318U4/DIA/BPM-01",
-    "R3-318U5/DIA/BPM-01",
-    "R3-318M2/DIA/BPM-01",
-    "R3-318M2/DIA/BPM-02",
-    "R3-319M1/DIA/BPM-01",
-    "R3-319M1/DIA/BPM-02",
-    "R3-319U1/DIA/BPM-01",
-    "R3-319U2/DIA/BPM-01",
-    "R3-319U3/DIA/BPM-01",
-    "R3-319U3/DIA/BPM-02",
-    "R3-319U4/DIA/BPM-01",
-    "R3-319U5/DIA/BPM-01",
-    "R3-319M2/DIA/BPM-01",
-    "R3-319M2/DIA/BPM-02",
-    "R3-320M1/DIA/BPM-01",
-    "R3-320M1/DIA/BPM-02",
-    "R3-320U1/DIA/BPM-01",
-    "R3-320U2/DIA/BPM-01",
-    "R3-320U3/DIA/BPM-01",
-    "R3-320U3/DIA/BPM-02",
-    "R3-320U4/DIA/BPM-01",
-    "R3-320U5/DIA/BPM-01",
-    "R3-320M2/DIA/BPM-01",
-    "R3-320M2/DIA/BPM-02",
-];
-
-const R1_BPMNAME_LIST: &[&str] = &[
-    "R1-101/DIA/BPM-01",
-    "R1-101/DIA/BPM-02",
-    "R1-101/DIA/BPM-03",
-    "R1-102/DIA/BPM-01",
-    "R1-102/DIA/BPM-02",
-    "R1-102/DIA/BPM-03",
-    "R1-103/DIA/BPM-01",
-    "R1-103/DIA/BPM-02",
-    "R1-103/DIA/BPM-03",
-    "R1-104/DIA/BPM-01",
-    "R1-104/DIA/BPM-02",
-    "R1-104/DIA/BPM-03",
-    "R1-105/DIA/BPM-01",
-    "R1-105/DIA/BPM-02",
-    "R1-105/DIA/BPM-03",
-    "R1-106/DIA/BPM-01",
-    "R1-106/DIA/BPM-02",
-    "R1-106/DIA/BPM-03",
-    "R1-107/DIA/BPM-01",
-    "R1-107/DIA/BPM-02",
-    "R1-107/DIA/BPM-03",
-    "R1-108/DIA/BPM-01",
-    "R1-108/DIA/BPM-02",
-    "R1-108/DIA/BPM-03",
-    "R1-109/DIA/BPM-01",
-    "R1-109/DIA/BPM-02",
-    "R1-109/DIA/BPM-03",
-    "R1-110/DIA/BPM-01",
-    "R1-110/DIA/BPM-02",
-    "R1-110/DIA/BPM-03",
-    "R1-111/DIA/BPM-01",
-    "R1-111/DIA/BPM-02",
-    "R1-111/DIA/BPM-03",
-    "R1-112/DIA/BPM-01",
-    "R1-112/DIA/BPM-02",
-    "R1-112/DIA/BPM-03",
-];
+use std::fmt::{self, Write as fmt_wrt};
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+
+/// The format of the sample times in the output files.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H:%M:%S%.f%:z";
 
 pub fn get_bpm_number(searchterms: &Vec<String>, ring: &Ring) -> Option<Vec<usize>> {
     let mut retval: Vec<usize> = vec![];
 
     for term in searchterms {
-        match get_bpm_number_individual_term(term.to_uppercase().as_str(), ring) {
-            Some(mut ans) => retval.append(&mut ans),
-            None => {}
+        if let Some(mut ans) = get_bpm_number_individual_term(term.to_uppercase().as_str(), ring) {
+            retval.append(&mut ans);
         }
     }
 
@@ -264,92 +31,501 @@ pub fn get_bpm_number(searchterms: &Vec<String>, ring: &Ring) -> Option<Vec<usiz
 }
 
 fn get_bpm_number_individual_term(searchterm: &str, ring: &Ring) -> Option<Vec<usize>> {
-    let re = match searchterm {
-        "MIK" => Regex::new("^(R3-301M2/DIA/BPM-02|R3-302M1/DIA/BPM-01)$").unwrap(),
-        "NANOMAX" => Regex::new("^(R3-302M2/DIA/BPM-02|R3-303M1/DIA/BPM-01)$").unwrap(),
-        "DANMAX" => Regex::new("^(R3-303M2/DIA/BPM-02|R3-304M1/DIA/BPM-01)$").unwrap(),
-        "BALDER" => Regex::new("^(R3-307M2/DIA/BPM-02|R3-308M1/DIA/BPM-01)$").unwrap(),
-        "COSAXS" => Regex::new("^(R3-309M2/DIA/BPM-02|R3-310M1/DIA/BPM-01)$").unwrap(),
-        "BIOMAX" => Regex::new("^(R3-310M2/DIA/BPM-02|R3-311M1/DIA/BPM-01)$").unwrap(),
-        "VERITAS" => Regex::new("^(R3-315M2/DIA/BPM-02|R3-316M1/DIA/BPM-01)$").unwrap(),
-        "HIPPIE" => Regex::new("^(R3-316M2/DIA/BPM-02|R3-317M1/DIA/BPM-01)$").unwrap(),
-        "SOFTIMAX" => Regex::new("^(R3-317M2/DIA/BPM-02|R3-318M1/DIA/BPM-01)$").unwrap(),
-        "FLEXPES" => Regex::new("^(R1-106/DIA/BPM-03|R1-107/DIA/BPM-01)$").unwrap(),
-        "SPECIES" => Regex::new("^(R1-107/DIA/BPM-03|R1-108/DIA/BPM-01)$").unwrap(),
-        "BLOCH" => Regex::new("^(R1-109/DIA/BPM-03|R1-110/DIA/BPM-01)$").unwrap(),
-        "MAXPEEM" => Regex::new("^(R1-110/DIA/BPM-03|R1-111/DIA/BPM-01)$").unwrap(),
-        "FINEST" => Regex::new("^(R1-111/DIA/BPM-03|R1-112/DIA/BPM-01)$").unwrap(),
-        _ => Regex::new(format!("^{}$", searchterm).as_str()).unwrap(),
+    let re = match ring.beamline(searchterm) {
+        Some(flanking) => Regex::new(
+            format!(
+                "^({})$",
+                flanking.iter().map(|x| regex::escape(x)).join("|")
+            )
+            .as_str(),
+        )
+        .unwrap(),
+        None => Regex::new(format!("^{}$", searchterm).as_str()).unwrap(),
     };
 
-    match *ring {
-        Ring::R1 => Some(
-            R1_BPMNAME_LIST
-                .iter()
-                .enumerate()
-                .filter(|x| re.is_match(x.1))
-                .map(|x| x.0 + 1)
-                .collect(),
-        ),
-        Ring::R3 => Some(
-            R3_BPMNAME_LIST
-                .iter()
-                .enumerate()
-                .filter(|x| re.is_match(x.1))
-                .map(|x| x.0 + 1)
-                .collect(),
-        ),
-        Ring::Unk => None,
+    if ring.bpm_names().is_empty() {
+        return None;
     }
+    Some(
+        ring.bpm_names()
+            .iter()
+            .enumerate()
+            .filter(|x| re.is_match(x.1))
+            .map(|x| x.0 + 1)
+            .collect(),
+    )
 }
 
 pub fn get_bpm_name(bpmnum: usize, ring: &Ring) -> Option<String> {
-    if *ring == Ring::R3 {
-        Some(R3_BPMNAME_LIST[bpmnum].to_string())
-    } else if *ring == Ring::R1 {
-        Some(R1_BPMNAME_LIST[bpmnum].to_string())
-    } else {
-        None
+    ring.bpm_names().get(bpmnum).cloned()
+}
+
+/// The cell of a BPM, e.g., `01` for `R3-301M1/DIA/BPM-01` or `R1-101/DIA/BPM-01`.
+pub fn get_bpm_cell(name: &str) -> Option<String> {
+    let (_, rest) = name.split_once('-')?;
+    rest.get(1..3)
+        .filter(|x| x.chars().all(|c| c.is_ascii_digit()))
+        .map(|x| x.to_string())
+}
+
+/// Parses a timestamp as written to the output files (`%Y-%m-%d_%H:%M:%S%.f%:z`).
+///
+/// Files written by earlier versions have no offset, so their times are taken to be in the time
+/// zone in use, and times repeated when the clocks go back are resolved as in
+/// `timezone::from_local_in_file`.
+pub fn parse_timestamp(
+    ts: &str,
+    not_before: Option<DateTime<FixedOffset>>,
+) -> std::result::Result<DateTime<FixedOffset>, String> {
+    if let Ok(time) = DateTime::parse_from_str(ts, TIMESTAMP_FORMAT) {
+        return Ok(time);
     }
+    let time = NaiveDateTime::parse_from_str(ts, "%Y-%m-%d_%H:%M:%S%.f")
+        .map_err(|_| format!("Could not understand the time '{}'", ts))?;
+    from_local_in_file(time, not_before)
+}
+
+/// Finds the ring and the (zero-based) BPM number of a BPM from its name.
+pub fn get_bpm_from_name(name: &str) -> Option<(Ring, usize)> {
+    ring_names().into_iter().find_map(|ring| {
+        let ring = Ring(ring.to_string());
+        let bpmnum = ring.bpm_names().iter().position(|x| x == name)?;
+        Some((ring, bpmnum))
+    })
 }
 
+/// An accelerator ring, such as R1 or R3, as named in the ring definitions (see `config`).  The
+/// default is an unknown ring, for data whose ring is not yet known.
 #[derive(PartialEq, Default, Debug, Clone)]
-pub enum Ring {
-    R1,
-    R3,
-    #[default]
-    Unk,
+pub struct Ring(String);
+
+impl Ring {
+    /// Finds a ring in the ring definitions by its name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        ring_names()
+            .into_iter()
+            .find(|x| x.eq_ignore_ascii_case(name))
+            .map(|x| Self(x.to_string()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// The names of the BPMs of the ring, in the order of their numbers in the archiver.
+    pub fn bpm_names(&self) -> &'static [String] {
+        ring_definition(&self.0)
+            .and_then(|x| x.bpms.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The beamlines of the ring, each with the BPMs flanking its insertion device.
+    pub fn beamlines(&self) -> Vec<(&'static str, &'static [String])> {
+        ring_definition(&self.0)
+            .map(|x| {
+                x.beamlines
+                    .iter()
+                    .map(|(name, bpms)| (name.as_str(), bpms.as_slice()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The BPMs flanking the insertion device of a beamline of the ring, found by its name
+    /// ignoring case.
+    pub fn beamline(&self, name: &str) -> Option<&'static [String]> {
+        ring_definition(&self.0)?
+            .beamlines
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, bpms)| bpms.as_slice())
+    }
+}
+
+impl fmt::Display for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "Unk")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// The sample times of an acquisition, shared by all of its BPMs.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TimeAxis {
+    /// The time of the first sample, in nanoseconds since the Unix epoch.
+    pub start_ns: i64,
+    /// The time between samples, in nanoseconds.
+    pub period_ns: f64,
+    pub len: usize,
+}
+
+impl TimeAxis {
+    /// The start must be within the range of times accepted by `timespec::check_time_range`,
+    /// which is checked wherever times come from outside.
+    pub fn new(start: &DateTime<FixedOffset>, period_ns: f64, len: usize) -> Self {
+        Self {
+            start_ns: start.timestamp_nanos_opt().unwrap(),
+            period_ns,
+            len,
+        }
+    }
+
+    /// Rebuilds a time axis from timestamps formatted as in the output files.
+    ///
+    /// Only the first and last timestamps are used, as the samples are evenly spaced.  If either
+    /// cannot be parsed, its index is returned along with the reason.
+    pub fn from_strings(ts: &[String]) -> std::result::Result<Self, (usize, String)> {
+        let (first, last) = match (ts.first(), ts.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(Self::default()),
+        };
+        let first = parse_timestamp(first, None).map_err(|e| (0, e))?;
+        let last = parse_timestamp(last, Some(first)).map_err(|e| (ts.len() - 1, e))?;
+        let period_ns = match ts.len() {
+            1 => 0.0,
+            n => match (last - first).num_nanoseconds() {
+                Some(ns) if ns >= 0 => ns as f64 / (n - 1) as f64,
+                _ => return Err((n - 1, "The times go backwards".to_string())),
+            },
+        };
+        check_time_range(first).map_err(|e| (0, e))?;
+        Ok(Self::new(&first, period_ns, ts.len()))
+    }
+
+    /// Rebuilds a time axis from sample times in nanoseconds since the Unix epoch.
+    pub fn from_nanos(ts: &[i64]) -> Self {
+        let n = ts.len();
+        Self {
+            start_ns: ts.first().copied().unwrap_or_default(),
+            period_ns: match n {
+                0 | 1 => 0.0,
+                _ => (ts[n - 1] - ts[0]) as f64 / (n - 1) as f64,
+            },
+            len: n,
+        }
+    }
+
+    pub fn time_ns(&self, i: usize) -> i64 {
+        self.start_ns + (i as f64 * self.period_ns) as i64
+    }
+
+    pub fn time(&self, i: usize) -> DateTime<FixedOffset> {
+        from_nanos(self.time_ns(i))
+    }
+
+    /// The sample times formatted as in the output files.
+    pub fn formatted(&self) -> FormattedTimes<'_> {
+        FormattedTimes {
+            axis: self,
+            i: 0,
+            second: None,
+            prefix: String::new(),
+            offset: String::new(),
+        }
+    }
+}
+
+/// Iterator over the formatted times of a `TimeAxis`.
+///
+/// Formatting dates is slow, so the date and time are only formatted once for all of the
+/// samples within each second.
+pub struct FormattedTimes<'a> {
+    axis: &'a TimeAxis,
+    i: usize,
+    second: Option<i64>,
+    prefix: String,
+    offset: String,
+}
+
+impl Iterator for FormattedTimes<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if self.i >= self.axis.len {
+            return None;
+        }
+        let ns = self.axis.time_ns(self.i);
+        self.i += 1;
+        let (second, nanos) = (ns.div_euclid(1_000_000_000), ns.rem_euclid(1_000_000_000));
+        if self.second != Some(second) {
+            let time = from_utc(DateTime::from_timestamp(second, 0).unwrap());
+            self.prefix = time.format("%Y-%m-%d_%H:%M:%S").to_string();
+            self.offset = time.format("%:z").to_string();
+            self.second = Some(second);
+        }
+        Some(format!("{}.{:09}{}", self.prefix, nanos, self.offset))
+    }
 }
 
+/// The positions measured by one BPM.
 #[derive(Debug, Default, Clone)]
 pub struct BpmData {
-    pub ring: Ring,
     pub bpmnum: usize,
-    pub ts: Vec<String>,
     pub x: Vec<i32>,
     pub y: Vec<i32>,
+    /// The positions as they were read, if they were read in physical units.  `x` and `y` then
+    /// hold them rounded to the nearest nm.
+    pub scaled: Option<ScaledPositions>,
 }
 
 impl BpmData {
-    pub fn write_to_file(self, basename: &str) {
-        let fname = format!("{}_{:03}.dat", basename, self.bpmnum);
-        let mut file = File::create(fname).unwrap();
+    /// Adds a position read from a file written in the given units.
+    pub fn push(&mut self, units: Units, x: f64, y: f64) {
+        self.x.push(units.to_raw(x));
+        self.y.push(units.to_raw(y));
+        if units != Units::Raw {
+            let scaled = self.scaled.get_or_insert_with(|| ScaledPositions {
+                units,
+                x: vec![],
+                y: vec![],
+            });
+            scaled.x.push(x);
+            scaled.y.push(y);
+        }
+    }
+
+    pub fn write_to_file(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        let mut file = compression.create(fname)?;
         writeln!(
             file,
             "\"# DATASET= tango://g-v-csdb-0.maxiv.lu.se:10000/{}/fa\"",
-            get_bpm_name(self.bpmnum, &self.ring).unwrap()
+            acq.bpm_name(self)
+        )?;
+        for (key, value) in acq.metadata() {
+            writeln!(file, "# {}: {}", key, value)?;
+        }
+        writeln!(file, "# bpm: {}", acq.bpm_name(self))?;
+        writeln!(file, "# bpm_id: {}", self.bpmnum + 1)?;
+        if acq.calibration.contains(&acq.bpm_name(self)) {
+            let c = acq.calibration.get(&acq.bpm_name(self));
+            writeln!(
+                file,
+                "# correction: x_gain={} x_offset={} y_gain={} y_offset={}",
+                c.x_gain, c.x_offset, c.y_gain, c.y_offset
+            )?;
+        }
+        match acq.units.name() {
+            Some(units) => writeln!(file, "# t [x, y] ({})", units)?,
+            None => writeln!(file, "# t [x, y]")?,
+        }
+
+        write!(file, "{}", self.output_string(acq))?;
+        file.finish()
+    }
+
+    pub fn output_string(&self, acq: &Acquisition) -> String {
+        let capacity = acq.ts.len * 100;
+        let (x, y) = acq.positions(self);
+        izip!(acq.ts.formatted(), 0..x.len().min(y.len())).fold(
+            String::with_capacity(capacity),
+            |mut acc, (t, i)| {
+                let _ = writeln!(acc, "{} [{}, {}]", t, x.get(i), y.get(i));
+                acc
+            },
+        )
+    }
+}
+
+/// Reads back a file written by `BpmData::write_to_file`.
+///
+/// The ring and BPM are recovered from the `# DATASET=` header line.  Compressed files are read
+/// transparently.
+pub fn read_dat(fname: &str) -> Result<Acquisition> {
+    let invalid = |line: usize, msg: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}:{}: {}", fname, line + 1, msg),
         )
+    };
+    let mut ring = Ring::default();
+    let mut units = Units::Raw;
+    let mut data = BpmData::default();
+    let mut ts = vec![];
+    // The line of each sample, for errors about its time.
+    let mut ts_lines = vec![];
+    let mut found_dataset = false;
+    let mut metadata = vec![];
+
+    for (i, line) in compress::open(fname)?.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.trim_matches('"').starts_with("# DATASET=") {
+            let name = line
+                .trim_matches('"')
+                .trim_end_matches("/fa")
+                .split_once("tango://")
+                .and_then(|(_, url)| url.split_once('/'))
+                .map(|(_, name)| name)
+                .ok_or_else(|| invalid(i, "Could not parse the DATASET line"))?;
+            (ring, data.bpmnum) = get_bpm_from_name(name)
+                .ok_or_else(|| invalid(i, format!("Unknown BPM '{}'", name).as_str()))?;
+            found_dataset = true;
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("# t [x, y]") {
+            if let Some(name) = name.trim().strip_prefix('(') {
+                units = name
+                    .strip_suffix(')')
+                    .and_then(Units::from_string)
+                    .ok_or_else(|| invalid(i, "Unknown units"))?;
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            metadata.extend(parse_header_line(line));
+            continue;
+        }
+
+        let (t, xy) = line
+            .split_once(' ')
+            .ok_or_else(|| invalid(i, "Expected a line of the form 't [x, y]'"))?;
+        let (x, y) = xy
+            .trim()
+            .strip_prefix('[')
+            .and_then(|xy| xy.strip_suffix(']'))
+            .and_then(|xy| xy.split_once(','))
+            .ok_or_else(|| invalid(i, "Expected a line of the form 't [x, y]'"))?;
+        ts.push(t.to_string());
+        ts_lines.push(i);
+        let x = units
+            .parse(x.trim())
+            .ok_or_else(|| invalid(i, "Could not parse the x value"))?;
+        let y = units
+            .parse(y.trim())
+            .ok_or_else(|| invalid(i, "Could not parse the y value"))?;
+        data.push(units, x, y);
+    }
+
+    if !found_dataset {
+        return Err(invalid(0, "No DATASET line was found"));
+    }
+    let ts = TimeAxis::from_strings(&ts).map_err(|(index, e)| invalid(ts_lines[index], &e))?;
+    let mut acq = Acquisition {
+        units,
+        ..Acquisition::new(ring, ts, vec![data])
+    };
+    acq.apply_metadata(&metadata);
+    Ok(acq)
+}
+
+/// Writes one `.dat` file per BPM.
+pub struct DatWriter;
+
+impl OutputWriter for DatWriter {
+    fn extension(&self) -> &'static str {
+        "dat"
+    }
+
+    fn per_bpm(&self) -> bool {
+        true
+    }
+
+    fn write_bpm(
+        &self,
+        acq: &Acquisition,
+        bpm: &BpmData,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        bpm.write_to_file(acq, fname, compression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+    use crate::timezone::{parse_timezone, set_timezone};
+
+    fn axis(ts: &[&str]) -> std::result::Result<TimeAxis, (usize, String)> {
+        set_timezone(Some(parse_timezone("Europe/Stockholm").unwrap()));
+        TimeAxis::from_strings(&ts.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+    }
+
+    fn time(arg: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(arg).unwrap()
+    }
+
+    #[test]
+    fn times_without_offsets_in_the_repeated_hour_are_read() {
+        let ts = axis(&[
+            "2023-10-29_02:30:00.000000000",
+            "2023-10-29_02:30:00.000100000",
+        ])
+        .unwrap();
+        assert_eq!(ts.time(0), time("2023-10-29T02:30:00+02:00"));
+        assert_eq!(ts.period_ns, 100_000.0);
+
+        // The times keep increasing as the clocks go back from 03:00 to 02:00.
+        let ts = axis(&[
+            "2023-10-29_02:59:59.999900000",
+            "2023-10-29_02:00:00.000000000",
+        ])
         .unwrap();
-        writeln!(file, "# t [x, y]").unwrap();
+        assert_eq!(ts.time(1), time("2023-10-29T02:00:00+01:00"));
+        assert_eq!(ts.period_ns, 100_000.0);
+    }
+
+    #[test]
+    fn unreadable_times_are_reported_with_their_index() {
+        let (index, error) = axis(&[
+            "2023-03-26_01:59:59.999900000",
+            "2023-03-26_02:00:00.000000000",
+        ])
+        .unwrap_err();
+        assert_eq!(index, 1);
+        assert!(error.contains("does not exist"), "{}", error);
+        assert_eq!(
+            axis(&["yesterday", "2023-10-04_12:00:00"]).unwrap_err().0,
+            0
+        );
+        assert_eq!(
+            axis(&["2023-10-04_12:00:00+02:00", "2023-10-04_11:00:00+02:00"])
+                .unwrap_err()
+                .0,
+            1
+        );
+    }
 
-        write!(file, "{}", self.output_string()).unwrap();
+    #[test]
+    fn times_with_offsets_are_read_as_written() {
+        let ts = axis(&[
+            "2023-10-29_02:30:00.000000000+01:00",
+            "2023-10-29_02:30:01.000000000+01:00",
+        ])
+        .unwrap();
+        assert_eq!(ts.time(0), time("2023-10-29T01:30:00Z"));
+        assert_eq!(ts.period_ns, 1e9);
     }
 
-    pub fn output_string(self) -> String {
-        let capacity = self.ts.len() * 100;
-        izip!(self.ts, self.x, self.y).fold(String::with_capacity(capacity), |mut acc, x| {
-            let _ = writeln!(acc, "{} [{}, {}]", x.0, x.1, x.2);
-            acc
-        })
+    #[test]
+    fn dat_round_trip() {
+        for (name, acq) in acquisitions() {
+            for bpm in &acq.bpms {
+                let written = Acquisition {
+                    bpms: vec![bpm.clone()],
+                    ..acq.clone()
+                };
+                let read = write_and_read(
+                    &written,
+                    &format!("{}-{}.dat", name, bpm.bpmnum),
+                    |acq, fname, compression| bpm.write_to_file(acq, fname, compression),
+                    read_dat,
+                );
+                assert_same_data(&read, &written);
+                assert_same_metadata(&read, &written);
+            }
+        }
     }
 }
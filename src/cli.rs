@@ -0,0 +1,390 @@
+use crate::bpmdata::Ring;
+use crate::compress::{Compression, Overwrite};
+use crate::config::ring_names;
+use crate::csvdata::Layout;
+use crate::estimate::parse_size;
+use crate::formats::OutputFormat;
+use crate::logging::LogFormat;
+use crate::timespec::{check_time_range, parse_duration, parse_time};
+use crate::timezone::{in_timezone, parse_timezone};
+use crate::units::{Calibration, Units};
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use clap::{Args, Parser, Subcommand};
+use clap_complete::Shell;
+use regex::Regex;
+
+/// Fast extraction of data from the MAX IV Fast Archiver.
+#[derive(Parser)]
+#[command(name = "fars", version = crate::VERSION_NUMBER)]
+pub struct Cli {
+    /// The format of the log messages written to stderr: text or json.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        value_parser = parse_log_format,
+        default_value = "text"
+    )]
+    pub log_format: LogFormat,
+
+    /// The time zone that times are given and written in, such as UTC or Europe/Stockholm.
+    /// Defaults to the local time zone of the machine.
+    #[arg(long, global = true, value_parser = parse_timezone)]
+    pub tz: Option<Tz>,
+
+    /// The host of the archiver, instead of the one in the configuration (by default, fa).
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// The port of the archiver, instead of the one for the ring in the configuration (by
+    /// default, 12001 for R1 and 32001 for R3).
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Download data for a time range from the archiver.
+    Get(GetArgs),
+    /// Search a time range for a beam dump, and download the data around it.
+    FindDump(GetArgs),
+    /// Stream data from the archiver to stdout as it arrives, until interrupted.
+    Live(LiveArgs),
+    /// Describe data files or manifests that have already been written.
+    Info {
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+    },
+    /// List the BPMs of a ring, or the beamlines that can be used to select them.
+    List(ListArgs),
+    /// Convert data files that have already been downloaded to another format.
+    Convert(ConvertArgs),
+    /// Check that the files listed in manifests have not changed since they were written.
+    Verify {
+        #[arg(required = true, value_name = "MANIFEST")]
+        manifests: Vec<String>,
+    },
+    /// Print a shell completion script.
+    Completions { shell: Shell },
+}
+
+/// What to ask of the archiver.
+#[derive(Args)]
+pub struct RequestArgs {
+    /// The accelerator ring, such as R1 or R3.
+    #[arg(long, value_parser = parse_ring)]
+    pub ring: Ring,
+    /// The start of the time range: YYYY-MM-DDThh:mm:ss.sss in the time zone given by --tz, an
+    /// RFC 3339 time such as 2023-10-04T10:00:00Z, Unix epoch seconds, now-10m, or
+    /// 'yesterday 14:00'.
+    #[arg(long, value_name = "TIME", required_unless_present = "at")]
+    pub start: Option<String>,
+    /// The end of the time range, in any of the forms accepted by --start.
+    #[arg(
+        long,
+        value_name = "TIME",
+        required_unless_present_any = ["duration", "samples", "at"]
+    )]
+    pub end: Option<String>,
+    /// The length of the time range, such as 30s, 10m or 1h30m, instead of --end.
+    #[arg(long, value_parser = parse_duration, conflicts_with = "end")]
+    pub duration: Option<Duration>,
+    /// The number of samples to request from --start, instead of --end (e.g., 1048576 for an
+    /// FFT).
+    #[arg(long, value_name = "N", conflicts_with_all = ["end", "duration"])]
+    pub samples: Option<u64>,
+    /// A time to request samples around with --before-samples and --after-samples, instead of
+    /// --start.
+    #[arg(
+        long,
+        value_name = "TIME",
+        conflicts_with_all = ["start", "end", "duration", "samples"]
+    )]
+    pub at: Option<String>,
+    /// The number of samples to request before --at.
+    #[arg(long, value_name = "N", requires = "at")]
+    pub before_samples: Option<u64>,
+    /// The number of samples to request from --at onwards.
+    #[arg(long, value_name = "N", requires = "at")]
+    pub after_samples: Option<u64>,
+    /// Regular expressions (matched against the whole BPM name) or beamline names selecting
+    /// the BPMs.  Every BPM of the ring is acquired if none are given.
+    #[arg(value_name = "BPM", value_parser = parse_bpm_search_term)]
+    pub bpms: Vec<String>,
+}
+
+/// The part of the archive that a request covers.
+pub enum RequestSpan {
+    /// From a start time until an end time.
+    Range(DateTime<FixedOffset>, DateTime<FixedOffset>),
+    /// A number of samples from a start time.
+    Samples(DateTime<FixedOffset>, u64),
+    /// A number of samples before and after a time.
+    Around {
+        at: DateTime<FixedOffset>,
+        before: u64,
+        after: u64,
+    },
+}
+
+impl RequestArgs {
+    /// The part of the archive requested.
+    ///
+    /// The times can only be parsed once the time zone given by `--tz` is known, so they are
+    /// left as strings by clap.
+    pub fn span(&self) -> Result<RequestSpan, String> {
+        let time =
+            |arg: &str, flag: &str| parse_time(arg).map_err(|e| format!("Invalid {flag}: {e}"));
+        if let Some(at) = &self.at {
+            return Ok(RequestSpan::Around {
+                at: time(at, "--at")?,
+                before: self.before_samples.unwrap_or_default(),
+                after: self.after_samples.unwrap_or_default(),
+            });
+        }
+        let start = time(self.start.as_deref().unwrap_or_default(), "--start")?;
+        Ok(match (&self.end, self.duration, self.samples) {
+            (Some(end), _, _) => RequestSpan::Range(start, time(end, "--end")?),
+            (None, Some(duration), _) => RequestSpan::Range(
+                start,
+                check_time_range(in_timezone(start + duration))
+                    .map_err(|e| format!("Invalid --duration: the end time {e}"))?,
+            ),
+            (None, None, Some(samples)) => RequestSpan::Samples(start, samples),
+            (None, None, None) => unreachable!("clap requires --end, --duration or --samples"),
+        })
+    }
+}
+
+#[derive(Args)]
+pub struct GetArgs {
+    #[command(flatten)]
+    pub request: RequestArgs,
+    /// Request data from the decimated stream.
+    #[arg(long)]
+    pub deci: bool,
+    /// Refuse to download anything estimated to need more memory or disk space than this, such
+    /// as 500M or 16G, unless --yes is given.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "4G")]
+    pub size_limit: u64,
+    /// Download even if the estimated size is over --size-limit.
+    #[arg(long, short)]
+    pub yes: bool,
+    /// Print the BPMs, time range, archiver commands and output files of the request, without
+    /// connecting to the archiver or writing anything.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Ask the archiver for the names of its BPMs first, warning about any that differ from the
+    /// ring definitions, and refusing to acquire BPMs whose names differ.
+    #[arg(long)]
+    pub check_bpms: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Args)]
+pub struct LiveArgs {
+    /// The accelerator ring, such as R1 or R3.
+    #[arg(long, value_parser = parse_ring)]
+    pub ring: Ring,
+    /// Regular expressions (matched against the whole BPM name) or beamline names selecting
+    /// the BPMs.  Every BPM of the ring is streamed if none are given.
+    #[arg(value_name = "BPM", value_parser = parse_bpm_search_term)]
+    pub bpms: Vec<String>,
+    /// Stop after this many samples, rather than when interrupted.
+    #[arg(long, value_name = "N")]
+    pub samples: Option<u64>,
+    /// The output format: csv, tsv or jsonl.
+    #[arg(long, value_parser = parse_live_format, default_value = "csv")]
+    pub format: OutputFormat,
+    /// The layout of the output: wide or long.
+    #[arg(long, value_parser = parse_layout, default_value = "wide")]
+    pub layout: Layout,
+    /// Write positions as floats in these units (nm, um or mm) rather than as raw integers.
+    #[arg(long, value_parser = parse_units)]
+    pub units: Option<Units>,
+    /// A file of per-BPM gains and offsets to correct the positions with.
+    #[arg(long, value_name = "FILE", value_parser = parse_calibration)]
+    pub calibration: Option<Calibration>,
+}
+
+/// `fars list --ring R3` is taken to mean `fars list bpms --ring R3`.
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct ListArgs {
+    #[command(subcommand)]
+    pub command: Option<ListCommand>,
+    /// The accelerator ring, such as R1 or R3.
+    #[arg(long, value_parser = parse_ring, required = true)]
+    pub ring: Option<Ring>,
+    /// Only list the BPMs matching these regular expressions or beamline names.
+    #[arg(value_name = "BPM", value_parser = parse_bpm_search_term)]
+    pub bpms: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum ListCommand {
+    /// List the BPMs of a ring, with their numbers, cells and the beamlines they flank.
+    Bpms(ListBpmsArgs),
+    /// List the beamlines that can be used to select BPMs, with the BPMs flanking them.
+    Beamlines {
+        /// Only list the beamlines of this ring.
+        #[arg(long, value_parser = parse_ring)]
+        ring: Option<Ring>,
+    },
+}
+
+#[derive(Args)]
+pub struct ListBpmsArgs {
+    /// The accelerator ring, such as R1 or R3.
+    #[arg(long, value_parser = parse_ring)]
+    pub ring: Ring,
+    /// Only list the BPMs matching these regular expressions or beamline names.
+    #[arg(value_name = "BPM", value_parser = parse_bpm_search_term)]
+    pub bpms: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+    /// The files to convert.  Files holding different BPMs of the same acquisition are merged.
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<String>,
+    /// The format to convert to, optionally followed by the output name.
+    #[arg(
+        long,
+        num_args = 1..=2,
+        value_names = ["FORMAT", "OUTPUT"],
+        conflicts_with = "format"
+    )]
+    pub to: Vec<String>,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// How and where to write the data.
+#[derive(Args)]
+pub struct OutputArgs {
+    /// The basename or template of the files to write, or - to write to stdout.
+    #[arg(
+        long,
+        visible_alias = "file",
+        value_name = "NAME",
+        default_value = "fa_data"
+    )]
+    pub output: String,
+    /// The output format: dat, raw, csv, tsv, jsonl, npz, parquet or hdf5.
+    #[arg(long, value_parser = parse_format, default_value = "dat")]
+    pub format: OutputFormat,
+    /// The layout of csv, tsv and jsonl outputs: wide or long.
+    #[arg(long, value_parser = parse_layout, default_value = "wide")]
+    pub layout: Layout,
+    /// Compress every file written: none, gzip or zstd, with an optional level (e.g., zstd:19).
+    #[arg(
+        long,
+        value_name = "COMPRESSION",
+        value_parser = parse_compression,
+        default_value = "none"
+    )]
+    pub compress: Compression,
+    /// Write positions as floats in these units (nm, um or mm) rather than as raw integers.
+    #[arg(long, value_parser = parse_units)]
+    pub units: Option<Units>,
+    /// A file of per-BPM gains and offsets to correct the positions with.
+    #[arg(long, value_name = "FILE", value_parser = parse_calibration)]
+    pub calibration: Option<Calibration>,
+    /// Overwrite files that already exist.
+    #[arg(long, conflicts_with = "no_clobber")]
+    pub force: bool,
+    /// Leave files that already exist alone, but still write the others.
+    #[arg(long)]
+    pub no_clobber: bool,
+}
+
+impl OutputArgs {
+    pub fn overwrite(&self) -> Overwrite {
+        if self.force {
+            Overwrite::Force
+        } else if self.no_clobber {
+            Overwrite::Skip
+        } else {
+            Overwrite::Refuse
+        }
+    }
+}
+
+fn parse_ring(arg: &str) -> Result<Ring, String> {
+    Ring::from_name(arg).ok_or_else(|| format!("expected one of {}", ring_names().join(", ")))
+}
+
+fn parse_bpm_search_term(arg: &str) -> Result<String, String> {
+    Regex::new(format!("^{}$", arg.to_uppercase()).as_str())
+        .map(|_| arg.to_string())
+        .map_err(|e| format!("not a valid regular expression: {e}"))
+}
+
+fn parse_format(arg: &str) -> Result<OutputFormat, String> {
+    OutputFormat::from_string(arg).ok_or_else(|| format!("unknown output format '{}'", arg))
+}
+
+/// Only text formats can be written a few samples at a time as the data arrives.
+fn parse_live_format(arg: &str) -> Result<OutputFormat, String> {
+    match parse_format(arg)? {
+        format @ (OutputFormat::Csv | OutputFormat::Tsv | OutputFormat::Jsonl) => Ok(format),
+        _ => Err("expected csv, tsv or jsonl".to_string()),
+    }
+}
+
+fn parse_layout(arg: &str) -> Result<Layout, String> {
+    match arg.to_lowercase().as_str() {
+        "wide" => Ok(Layout::Wide),
+        "long" => Ok(Layout::Long),
+        _ => Err("expected wide or long".to_string()),
+    }
+}
+
+fn parse_compression(arg: &str) -> Result<Compression, String> {
+    Compression::from_string(arg).ok_or_else(|| format!("unknown compression '{}'", arg))
+}
+
+fn parse_units(arg: &str) -> Result<Units, String> {
+    Units::from_string(arg).ok_or_else(|| format!("unknown units '{}'", arg))
+}
+
+fn parse_calibration(arg: &str) -> Result<Calibration, String> {
+    Calibration::read_from_file(arg).map_err(|e| e.to_string())
+}
+
+fn parse_log_format(arg: &str) -> Result<LogFormat, String> {
+    LogFormat::from_string(arg).ok_or_else(|| "expected text or json".to_string())
+}
+
+/// Rewrites the command line used before there were subcommands, such as
+/// `fars --ring R3 --start ... --find_dump`, as the equivalent `get` or `find-dump` command.
+pub fn legacy_args(mut args: Vec<String>) -> Vec<String> {
+    // The global options may come before everything else.
+    let mut pos = 1;
+    while args
+        .get(pos)
+        .is_some_and(|x| ["--log-format", "--tz", "--host", "--port"].contains(&x.as_str()))
+    {
+        pos += 2;
+    }
+    match args.get(pos) {
+        Some(first) if first.starts_with("--") && first != "--help" && first != "--version" => {}
+        _ => return args,
+    }
+    let command = match args.iter().position(|x| x == "--find_dump") {
+        Some(find_dump) => {
+            args.remove(find_dump);
+            "find-dump"
+        }
+        None => "get",
+    };
+    args.insert(pos.min(args.len()), command.to_string());
+    args
+}
@@ -0,0 +1,242 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::{stdout, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The output name that sends data to stdout rather than to a file.
+pub const STDOUT: &str = "-";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+static OVERWRITE: AtomicU8 = AtomicU8::new(0);
+
+/// What to do about output files that already exist.
+#[derive(Default, PartialEq, Debug, Clone, Copy)]
+pub enum Overwrite {
+    /// Fail without writing anything.
+    #[default]
+    Refuse,
+    /// Leave the existing files alone, write the others, and then fail (`--no-clobber`).
+    Skip,
+    /// Replace the existing files (`--force`).
+    Force,
+}
+
+pub fn set_overwrite(overwrite: Overwrite) {
+    OVERWRITE.store(overwrite as u8, Ordering::Relaxed);
+}
+
+pub fn overwrite() -> Overwrite {
+    match OVERWRITE.load(Ordering::Relaxed) {
+        1 => Overwrite::Skip,
+        2 => Overwrite::Force,
+        _ => Overwrite::Refuse,
+    }
+}
+
+/// Runs `f` with a different overwrite setting, such as for scratch files that should always be
+/// replaced whatever `--force` or `--no-clobber` say.
+pub fn with_overwrite<T>(overwrite: Overwrite, f: impl FnOnce() -> T) -> T {
+    let previous = OVERWRITE.swap(overwrite as u8, Ordering::Relaxed);
+    let result = f();
+    OVERWRITE.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Whether writing `fname` would replace an existing file that we have not been allowed to
+/// replace.
+pub fn would_clobber(fname: &str) -> bool {
+    fname != STDOUT && overwrite() != Overwrite::Force && Path::new(fname).exists()
+}
+
+/// Fails if any of the given files already exists, unless `--force` was given.
+pub fn check_overwrite<S: AsRef<str>>(fnames: &[S]) -> Result<()> {
+    let existing: Vec<&str> = fnames
+        .iter()
+        .map(|x| x.as_ref())
+        .filter(|x| would_clobber(x))
+        .collect();
+    match existing.as_slice() {
+        [] => Ok(()),
+        [fname] => Err(already_exists(format!("'{}' already exists", fname))),
+        [fname, rest @ ..] => Err(already_exists(format!(
+            "'{}' and {} more of the output files already exist",
+            fname,
+            rest.len()
+        ))),
+    }
+}
+
+fn already_exists(msg: String) -> Error {
+    Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} (use --force to overwrite)", msg),
+    )
+}
+
+#[derive(Default, PartialEq, Debug, Clone)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip(u32),
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Parses expressions of the form `gzip`, `zstd`, `gzip:9` or `zstd:19`.
+    pub fn from_string(arg: &str) -> Option<Self> {
+        let (name, level) = match arg.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (arg, None),
+        };
+        match name.to_lowercase().as_str() {
+            "none" if level.is_none() => Some(Self::None),
+            "gzip" | "gz" => match level {
+                Some(level) => level.parse().ok().filter(|x| *x <= 9).map(Self::Gzip),
+                None => Some(Self::Gzip(6)),
+            },
+            "zstd" | "zst" => match level {
+                Some(level) => level.parse().ok().map(Self::Zstd),
+                None => Some(Self::Zstd(3)),
+            },
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip(_) => ".gz",
+            Self::Zstd(_) => ".zst",
+        }
+    }
+
+    /// Creates `fname` with the extension for this compression appended to it.
+    ///
+    /// If `fname` is [`STDOUT`], the data is written to stdout instead.  Otherwise the data is
+    /// written to a temporary file next to it, which only replaces `fname` once
+    /// [`OutputFile::finish`] succeeds, so that a failed write never leaves a truncated file
+    /// behind.  Existing files are only replaced if `--force` was given.
+    pub fn create(&self, fname: &str) -> Result<OutputFile> {
+        let (sink, pending): (Box<dyn Write + Send>, _) = if fname == STDOUT {
+            (Box::new(stdout()), None)
+        } else {
+            let dest = PathBuf::from(format!("{}{}", fname, self.extension()));
+            check_overwrite(&[dest.to_string_lossy()])?;
+            if let Some(dir) = dest.parent() {
+                create_dir_all(dir)?;
+            }
+            let tmp = dest.with_file_name(format!(
+                ".{}.{}.tmp",
+                dest.file_name().unwrap_or_default().to_string_lossy(),
+                std::process::id()
+            ));
+            let file = File::create(&tmp)?;
+            (Box::new(file), Some(PendingRename { tmp, dest }))
+        };
+        let file = BufWriter::new(sink);
+        let stream = match self {
+            Self::None => Stream::Plain(file),
+            Self::Gzip(level) => {
+                Stream::Gzip(GzEncoder::new(file, flate2::Compression::new(*level)))
+            }
+            Self::Zstd(level) => Stream::Zstd(zstd::Encoder::new(file, *level)?),
+        };
+        Ok(OutputFile { stream, pending })
+    }
+}
+
+/// A file being written, possibly through a compressor.
+///
+/// `finish` must be called once everything has been written, so that the compressed stream is
+/// terminated properly, any errors in doing so are reported, and the file is moved into place.
+/// If it is dropped without being finished, the partial file is removed.
+pub struct OutputFile {
+    stream: Stream,
+    pending: Option<PendingRename>,
+}
+
+enum Stream {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    Gzip(GzEncoder<BufWriter<Box<dyn Write + Send>>>),
+    Zstd(zstd::Encoder<'static, BufWriter<Box<dyn Write + Send>>>),
+}
+
+/// A temporary file that is renamed to its final name once it has been written completely.
+struct PendingRename {
+    tmp: PathBuf,
+    dest: PathBuf,
+}
+
+impl Drop for PendingRename {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.tmp);
+    }
+}
+
+impl OutputFile {
+    pub fn finish(self) -> Result<()> {
+        let mut file = match self.stream {
+            Stream::Plain(file) => file,
+            Stream::Gzip(encoder) => encoder.finish()?,
+            Stream::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()?;
+        drop(file);
+        match &self.pending {
+            Some(pending) => rename(&pending.tmp, &pending.dest),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match &mut self.stream {
+            Stream::Plain(file) => file.write(buf),
+            Stream::Gzip(encoder) => encoder.write(buf),
+            Stream::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &mut self.stream {
+            Stream::Plain(file) => file.flush(),
+            Stream::Gzip(encoder) => encoder.flush(),
+            Stream::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Builds the name of an output file from a basename and a suffix, unless output is going to
+/// stdout.
+pub fn output_name(basename: &str, suffix: &str) -> String {
+    if basename == STDOUT {
+        STDOUT.to_string()
+    } else {
+        format!("{}{}", basename, suffix)
+    }
+}
+
+/// Opens a file for reading, transparently decompressing it if it is gzip or zstd compressed.
+pub fn open(fname: &str) -> Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(fname)?);
+    let magic = file.fill_buf()?;
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Reads the whole of a possibly compressed file.
+pub fn read_to_end(fname: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    open(fname)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
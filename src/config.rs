@@ -0,0 +1,264 @@
+use crate::bpmdata::Ring;
+use clap::Command;
+use itertools::Itertools;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// The host of the archiver at MAX IV, used unless the configuration or `--host` says otherwise.
+const DEFAULT_HOST: &str = "fa";
+
+/// The built-in ring definitions, which the configuration files add to.
+const BUILTIN_RINGS: &str = include_str!("rings.toml");
+
+/// The configuration in use, read from the configuration files.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The host and port given by `--host` and `--port`.
+static ADDRESS_OVERRIDE: OnceLock<(Option<String>, Option<u16>)> = OnceLock::new();
+
+/// The contents of a configuration file, such as
+///
+/// ```toml
+/// host = "localhost"
+///
+/// [rings.R3]
+/// port = 42001
+///
+/// [rings.SPF]
+/// host = "spf-fa"
+/// port = 52001
+/// bpms = ["I-SP02/DIA/BPM-01", "I-SP02/DIA/BPM-02"]
+///
+/// [defaults]
+/// format = "csv"
+/// size-limit = "16G"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The host of the archiver for every ring without a host of its own.
+    pub host: Option<String>,
+    #[serde(default)]
+    pub rings: BTreeMap<String, RingConfig>,
+    /// Defaults for command line options, keyed by their long names.
+    #[serde(default)]
+    pub defaults: BTreeMap<String, toml::Value>,
+}
+
+/// The definition of one ring: where to find its archiver and what its BPMs are called.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RingConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// The names of the BPMs, in the order of their numbers in the archiver.
+    pub bpms: Option<Vec<String>>,
+    /// The two BPMs flanking the insertion device of each beamline.
+    #[serde(default)]
+    pub beamlines: BTreeMap<String, Vec<String>>,
+}
+
+/// The configuration files, in the order they are read.  Settings in later files take precedence.
+fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from("/etc/fars/config.toml")];
+    if let Some(dir) = dirs::config_dir() {
+        files.push(dir.join("fars").join("config.toml"));
+    }
+    files
+}
+
+impl Config {
+    fn builtin() -> Self {
+        toml::from_str(BUILTIN_RINGS).expect("The built-in ring definitions are valid")
+    }
+
+    /// Reads the system-wide and per-user configuration files, if they exist, on top of the
+    /// built-in ring definitions.
+    pub fn load() -> Result<Self> {
+        let mut config = Self::builtin();
+        for path in config_files() {
+            let text = match read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let file: Self = toml::from_str(&text).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Could not read '{}': {e}", path.display()),
+                )
+            })?;
+            config.merge(file);
+        }
+        for (name, ring) in &config.rings {
+            ring.check(name)?;
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Self) {
+        if other.host.is_some() {
+            self.host = other.host;
+        }
+        for (name, ring) in other.rings {
+            // Rings are matched ignoring case, as on the command line.
+            let name = self
+                .rings
+                .keys()
+                .find(|x| x.eq_ignore_ascii_case(&name))
+                .cloned()
+                .unwrap_or(name);
+            let entry = self.rings.entry(name).or_default();
+            if ring.host.is_some() {
+                entry.host = ring.host;
+            }
+            if ring.port.is_some() {
+                entry.port = ring.port;
+            }
+            if let Some(bpms) = ring.bpms {
+                // Beamlines defined earlier next to BPMs that are no longer listed are dropped.
+                entry
+                    .beamlines
+                    .retain(|_, flanking| flanking.iter().all(|x| bpms.contains(x)));
+                entry.bpms = Some(bpms);
+            }
+            entry.beamlines.extend(ring.beamlines);
+        }
+        self.defaults.extend(other.defaults);
+    }
+
+    /// Makes the defaults of the configuration the defaults of the command line options, so that
+    /// they are checked in the same way and shown by `--help`.
+    pub fn apply_defaults(&self, mut command: Command) -> Result<Command> {
+        for (name, value) in &self.defaults {
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                        "The default for '{}' in the configuration must be a string or a number",
+                        name
+                    ),
+                    ))
+                }
+            };
+            let mut found = false;
+            command = with_default(command, name, &value, &mut found);
+            if !found {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "'{}' in the [defaults] of the configuration is not an option of fars",
+                        name
+                    ),
+                ));
+            }
+        }
+        Ok(command)
+    }
+}
+
+/// Sets the default of the option with the given long name in a command and all of its
+/// subcommands.  Options that are only switched on or off cannot be given defaults.
+fn with_default(mut command: Command, name: &str, value: &str, found: &mut bool) -> Command {
+    let id = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(name) && arg.get_action().takes_values())
+        .map(|arg| arg.get_id().clone());
+    if let Some(id) = id {
+        *found = true;
+        command = command.mut_arg(id, |arg| arg.default_value(value.to_string()));
+    }
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|x| x.get_name().to_string())
+        .collect();
+    for subcommand in subcommands {
+        command = command.mut_subcommand(subcommand, |x| with_default(x, name, value, found));
+    }
+    command
+}
+
+impl RingConfig {
+    /// Checks that a ring can be used: that its archiver and BPMs are known, and that its
+    /// beamlines refer to its BPMs.
+    fn check(&self, name: &str) -> Result<()> {
+        let invalid = |msg: String| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Ring '{}' in the configuration {}", name, msg),
+            )
+        };
+        if self.port.is_none() {
+            return Err(invalid("has no port".to_string()));
+        }
+        let bpms = match &self.bpms {
+            Some(bpms) if !bpms.is_empty() => bpms,
+            _ => return Err(invalid("has no BPMs".to_string())),
+        };
+        if let Some(bpm) = bpms.iter().duplicates().next() {
+            return Err(invalid(format!("lists the BPM '{}' more than once", bpm)));
+        }
+        for (beamline, flanking) in &self.beamlines {
+            if let Some(bpm) = flanking.iter().find(|x| !bpms.contains(x)) {
+                return Err(invalid(format!(
+                    "has the beamline '{}' next to '{}', which is not one of its BPMs",
+                    beamline, bpm
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the configuration in use.  This must be done before the command line is parsed, as the
+/// rings are checked against it.
+pub fn set_config(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Sets the host and port given on the command line, which take precedence over the
+/// configuration.
+pub fn set_address_override(host: Option<String>, port: Option<u16>) {
+    let _ = ADDRESS_OVERRIDE.set((host, port));
+}
+
+fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::builtin)
+}
+
+/// The names of the rings that are defined.
+pub fn ring_names() -> Vec<&'static str> {
+    config().rings.keys().map(|x| x.as_str()).collect()
+}
+
+/// The definition of a ring, found by its name ignoring case.
+pub fn ring_definition(name: &str) -> Option<&'static RingConfig> {
+    config()
+        .rings
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(name))
+        .map(|(_, ring)| ring)
+}
+
+/// The host and port of the archiver for a ring.
+pub fn archiver_address(ring: &Ring) -> (String, u16) {
+    let config = config();
+    let ring_config = ring_definition(ring.name())
+        .unwrap_or_else(|| unreachable!("Shouldn't be able to get here..."));
+    let (host_override, port_override) = ADDRESS_OVERRIDE.get().cloned().unwrap_or_default();
+    let host = host_override
+        .or(ring_config.host.clone())
+        .or(config.host.clone())
+        .unwrap_or(DEFAULT_HOST.to_string());
+    // Every ring is checked to have a port when the configuration is read.
+    let port = port_override.or(ring_config.port).unwrap_or_default();
+    (host, port)
+}
@@ -0,0 +1,268 @@
+use crate::acquisition::{parse_header_line, Acquisition};
+use crate::bpmdata::{get_bpm_from_name, TimeAxis};
+use crate::compress::{self, Compression};
+use crate::units::Units;
+use crate::writer::OutputWriter;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+
+#[derive(Default, PartialEq, Debug, Clone)]
+pub enum Layout {
+    /// One row per sample, with an x and a y column for every BPM.
+    #[default]
+    Wide,
+    /// One row per sample per BPM.
+    Long,
+}
+
+/// Writes the whole acquisition as a single CSV or TSV table.
+pub struct CsvWriter {
+    pub delimiter: char,
+    pub layout: Layout,
+}
+
+impl OutputWriter for CsvWriter {
+    fn extension(&self) -> &'static str {
+        if self.delimiter == '\t' {
+            "tsv"
+        } else {
+            "csv"
+        }
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        write_combined(acq, fname, self.delimiter, &self.layout, compression)
+    }
+}
+
+/// Writes every BPM of an acquisition into a single delimited table.
+///
+/// The table is preceded by `# key: value` lines describing the acquisition.
+pub fn write_combined(
+    acq: &Acquisition,
+    fname: &str,
+    delimiter: char,
+    layout: &Layout,
+    compression: &Compression,
+) -> Result<()> {
+    let mut file = compression.create(fname)?;
+    write_metadata(&mut file, &acq.metadata())?;
+    write_header(&mut file, acq, delimiter, layout)?;
+    write_rows(&mut file, acq, delimiter, layout)?;
+    file.finish()
+}
+
+/// Writes the `# key: value` lines that precede a table.
+pub fn write_metadata(file: &mut impl Write, metadata: &[(&str, String)]) -> Result<()> {
+    for (key, value) in metadata {
+        writeln!(file, "# {}: {}", key, value)?;
+    }
+    Ok(())
+}
+
+/// Writes the names of the columns of a table holding the BPMs of an acquisition.
+pub fn write_header(
+    file: &mut impl Write,
+    acq: &Acquisition,
+    delimiter: char,
+    layout: &Layout,
+) -> Result<()> {
+    let units = match acq.units.name() {
+        Some(units) => format!("[{}]", units),
+        None => String::new(),
+    };
+    match layout {
+        Layout::Wide => {
+            write!(file, "t")?;
+            for name in acq.bpm_names() {
+                write!(file, "{d}{name}_x{units}{d}{name}_y{units}", d = delimiter)?;
+            }
+            writeln!(file)
+        }
+        Layout::Long => writeln!(file, "t{d}bpm{d}x{units}{d}y{units}", d = delimiter),
+    }
+}
+
+/// Writes the rows of a table for every sample of an acquisition, without the header.
+pub fn write_rows(
+    file: &mut impl Write,
+    acq: &Acquisition,
+    delimiter: char,
+    layout: &Layout,
+) -> Result<()> {
+    let names = acq.bpm_names();
+    let positions: Vec<_> = acq.bpms.iter().map(|bpm| acq.positions(bpm)).collect();
+    let ts = acq.ts.formatted();
+
+    match layout {
+        Layout::Wide => {
+            for (i, t) in ts.enumerate() {
+                write!(file, "{}", t)?;
+                for (x, y) in &positions {
+                    write!(file, "{d}{}{d}{}", x.get(i), y.get(i), d = delimiter)?;
+                }
+                writeln!(file)?;
+            }
+        }
+        Layout::Long => {
+            for (i, t) in ts.enumerate() {
+                for ((x, y), name) in positions.iter().zip(&names) {
+                    writeln!(
+                        file,
+                        "{t}{d}{name}{d}{}{d}{}",
+                        x.get(i),
+                        y.get(i),
+                        d = delimiter
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a table written by `write_combined`, in either layout.
+pub fn read_combined(fname: &str, delimiter: char) -> Result<Acquisition> {
+    let invalid = |line: usize, msg: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}:{}: {}", fname, line + 1, msg),
+        )
+    };
+    let bpm_from_name = |line: usize, name: &str| {
+        get_bpm_from_name(name)
+            .ok_or_else(|| invalid(line, format!("Unknown BPM '{}'", name).as_str()))
+    };
+    let parse = |line: usize, val: Option<&str>, units: Units| -> Result<f64> {
+        val.and_then(|x| units.parse(x.trim()))
+            .ok_or_else(|| invalid(line, "Could not parse a position"))
+    };
+
+    // The header row may be preceded by a block of `#` lines describing the acquisition.
+    let mut lines = compress::open(fname)?.lines().enumerate();
+    let mut metadata = vec![];
+    let (h, header) = loop {
+        match lines.next() {
+            Some((h, line)) => {
+                let line = line?;
+                if !line.starts_with('#') {
+                    break (h, line);
+                }
+                metadata.extend(parse_header_line(&line));
+            }
+            None => return Ok(Acquisition::default()),
+        }
+    };
+    let unknown_units = || invalid(h, "Unknown units in the header");
+    let columns: Vec<&str> = header.trim().split(delimiter).collect();
+    let mut acq = Acquisition::default();
+    let mut ts = vec![];
+    // The line of each sample, for errors about its time.
+    let mut ts_lines = vec![];
+    let time_axis = |ts: &[String], ts_lines: &[usize]| {
+        TimeAxis::from_strings(ts).map_err(|(index, e)| invalid(ts_lines[index], &e))
+    };
+
+    if columns.len() == 4 && columns[..2] == ["t", "bpm"] {
+        let units = match (split_units(columns[2]), split_units(columns[3])) {
+            (Some(("x", x_units)), Some(("y", y_units))) if x_units == y_units => x_units,
+            _ => return Err(unknown_units()),
+        };
+        for (i, line) in lines {
+            let line = line?;
+            let mut fields = line.trim().split(delimiter);
+            let t = fields.next().unwrap_or_default();
+            let (ring, bpmnum) = bpm_from_name(i, fields.next().unwrap_or_default())?;
+            let bpm = acq
+                .get_or_insert_bpm(ring, bpmnum)
+                .map_err(|e| invalid(i, &e.to_string()))?;
+            let x = parse(i, fields.next(), units)?;
+            let y = parse(i, fields.next(), units)?;
+            bpm.push(units, x, y);
+            if bpm.x.len() > ts.len() {
+                ts.push(t.to_string());
+                ts_lines.push(i);
+            }
+        }
+        acq.set_time_axis(time_axis(&ts, &ts_lines)?);
+        acq.apply_metadata(&metadata);
+        acq.units = units;
+        return Ok(acq);
+    }
+
+    if columns.first() != Some(&"t") || columns.len() % 2 != 1 {
+        return Err(invalid(h, "Unrecognised header"));
+    }
+    let mut units = None;
+    for pair in columns[1..].chunks(2) {
+        let (x_name, y_name) = match (split_units(pair[0]), split_units(pair[1])) {
+            (Some((x_name, x_units)), Some((y_name, y_units)))
+                if x_units == y_units && *units.get_or_insert(x_units) == x_units =>
+            {
+                (x_name, y_name)
+            }
+            _ => return Err(unknown_units()),
+        };
+        let name = x_name
+            .strip_suffix("_x")
+            .filter(|name| y_name.strip_suffix("_y") == Some(name))
+            .ok_or_else(|| invalid(h, "Expected the columns to be pairs of '<bpm>_x, <bpm>_y'"))?;
+        let (ring, bpmnum) = bpm_from_name(h, name)?;
+        acq.get_or_insert_bpm(ring, bpmnum)
+            .map_err(|e| invalid(h, &e.to_string()))?;
+    }
+    let units = units.unwrap_or_default();
+    for (i, line) in lines {
+        let line = line?;
+        let mut fields = line.trim().split(delimiter);
+        ts.push(fields.next().unwrap_or_default().to_string());
+        ts_lines.push(i);
+        for bpm in acq.bpms.iter_mut() {
+            let x = parse(i, fields.next(), units)?;
+            let y = parse(i, fields.next(), units)?;
+            bpm.push(units, x, y);
+        }
+    }
+    acq.set_time_axis(time_axis(&ts, &ts_lines)?);
+    acq.apply_metadata(&metadata);
+    acq.units = units;
+    Ok(acq)
+}
+
+/// Splits a column name of the form `name[units]` into its name and units.
+fn split_units(column: &str) -> Option<(&str, Units)> {
+    match column.strip_suffix(']').and_then(|x| x.rsplit_once('[')) {
+        Some((name, units)) => Some((name, Units::from_string(units)?)),
+        None => Some((column, Units::Raw)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+
+    #[test]
+    fn csv_and_tsv_round_trip() {
+        for (name, acq) in acquisitions() {
+            for delimiter in [',', '\t'] {
+                for layout in [Layout::Wide, Layout::Long] {
+                    let read = write_and_read(
+                        &acq,
+                        &format!("{}-{:?}-{}.csv", name, layout, delimiter.escape_default()),
+                        |acq, fname, compression| {
+                            write_combined(acq, fname, delimiter, &layout, compression)
+                        },
+                        |fname| read_combined(fname, delimiter),
+                    );
+                    assert_same_data(&read, &acq);
+                    assert_same_metadata(&read, &acq);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::csvdata::Layout;
+use crate::formats::OutputFormat;
+use serde_json::{json, Value};
+
+/// The bytes sent by the archiver for each sample of each BPM: an i32 for each of x and y.
+const BYTES_PER_POSITION: u64 = 8;
+
+/// What an acquisition is expected to cost, worked out before anything is asked of the archiver.
+pub struct SizeEstimate {
+    pub samples: u64,
+    pub bpms: usize,
+    /// The bytes sent by the archiver.
+    pub transfer: u64,
+    /// The memory needed to hold the data and write it out.
+    pub memory: u64,
+    /// The size of the files written, before any compression.
+    pub output: u64,
+}
+
+impl SizeEstimate {
+    pub fn new(samples: u64, bpms: usize, format: &OutputFormat, layout: &Layout) -> Self {
+        // Absurd requests saturate, so that they are refused by the size limit rather than
+        // overflowing.
+        let transfer = samples.saturating_mul(bpms as u64 * BYTES_PER_POSITION);
+        let output = output_size(samples, bpms as u64, format, layout);
+        // Everything but raw output is written from the parsed positions, which are a second
+        // copy of the payload, and npz and parquet files are built in memory before being
+        // written.
+        let memory = match format {
+            OutputFormat::Raw => transfer,
+            OutputFormat::Npz | OutputFormat::Parquet => {
+                transfer.saturating_mul(2).saturating_add(output)
+            }
+            _ => transfer.saturating_mul(2),
+        };
+        Self {
+            samples,
+            bpms,
+            transfer,
+            memory,
+            output,
+        }
+    }
+
+    /// The larger of the memory needed and the size of the output, which is what is checked
+    /// against `--size-limit`.
+    pub fn largest(&self) -> u64 {
+        self.memory.max(self.output)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} samples of {} BPMs: about {} to download, {} of memory and {} of output",
+            self.samples,
+            self.bpms,
+            format_size(self.transfer),
+            format_size(self.memory),
+            format_size(self.output)
+        )
+    }
+
+    pub fn log_fields(&self) -> Vec<(&str, Value)> {
+        vec![
+            ("samples", json!(self.samples)),
+            ("num_bpms", json!(self.bpms)),
+            ("transfer_bytes", json!(self.transfer)),
+            ("memory_bytes", json!(self.memory)),
+            ("output_bytes", json!(self.output)),
+        ]
+    }
+}
+
+/// The rough size of the files written in each format.  Text formats spend about 7 characters
+/// on each position, and 35 on each timestamp.
+fn output_size(samples: u64, bpms: u64, format: &OutputFormat, layout: &Layout) -> u64 {
+    const TIMESTAMP: u64 = 35;
+    const NAME: u64 = 20;
+    let per_sample = match (format, layout) {
+        (OutputFormat::Raw, _) => bpms * BYTES_PER_POSITION,
+        // A line of `{timestamp} [{x}, {y}]` in the file of each BPM.
+        (OutputFormat::Dat, _) => bpms * (TIMESTAMP + 21),
+        (OutputFormat::Csv | OutputFormat::Tsv, Layout::Wide) => TIMESTAMP + bpms * 16,
+        (OutputFormat::Csv | OutputFormat::Tsv, Layout::Long) => bpms * (TIMESTAMP + NAME + 18),
+        (OutputFormat::Jsonl, Layout::Wide) => TIMESTAMP + 20 + bpms * 2 * (NAME + 12),
+        (OutputFormat::Jsonl, Layout::Long) => bpms * (TIMESTAMP + 20),
+        (OutputFormat::Npz | OutputFormat::Parquet | OutputFormat::Hdf5, _) => {
+            8 + bpms * BYTES_PER_POSITION
+        }
+    };
+    samples.saturating_mul(per_sample)
+}
+
+/// Parses a size such as `500M`, `4G`, `4GB` or `2GiB`.  A number on its own is in bytes.
+pub fn parse_size(arg: &str) -> Result<u64, String> {
+    let invalid = || format!("expected a size such as 500M, 4G or 2GiB, not '{}'", arg);
+    let arg = arg.trim();
+    let number_len = arg
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(arg.len());
+    let number: f64 = arg[..number_len].parse().map_err(|_| invalid())?;
+    let unit = match arg[number_len..].trim().to_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1e3,
+        "M" | "MB" => 1e6,
+        "G" | "GB" => 1e9,
+        "T" | "TB" => 1e12,
+        "KIB" => 1024.0,
+        "MIB" => 1024.0f64.powi(2),
+        "GIB" => 1024.0f64.powi(3),
+        "TIB" => 1024.0f64.powi(4),
+        _ => return Err(invalid()),
+    };
+    Ok((number * unit).round() as u64)
+}
+
+/// Formats a number of bytes for people, such as `1.6 GB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
@@ -0,0 +1,94 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{read_dat, DatWriter};
+use crate::csvdata::{read_combined, CsvWriter, Layout};
+use crate::hdf5data::{read_hdf5, Hdf5Writer};
+use crate::jsonldata::{read_jsonl, JsonlWriter};
+use crate::npzdata::{read_npz, NpzWriter};
+use crate::parquetdata::{read_parquet, ParquetWriter};
+use crate::rawdata::{RawData, RawWriter};
+use crate::writer::OutputWriter;
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Default, PartialEq, Debug, Clone)]
+pub enum OutputFormat {
+    #[default]
+    Dat,
+    Raw,
+    Csv,
+    Tsv,
+    Jsonl,
+    Npz,
+    Parquet,
+    Hdf5,
+}
+
+impl OutputFormat {
+    pub fn from_string(arg: &str) -> Option<Self> {
+        match arg.to_lowercase().as_str() {
+            "dat" => Some(Self::Dat),
+            "raw" | "bin" => Some(Self::Raw),
+            "csv" => Some(Self::Csv),
+            "tsv" => Some(Self::Tsv),
+            "jsonl" => Some(Self::Jsonl),
+            "npz" => Some(Self::Npz),
+            "parquet" => Some(Self::Parquet),
+            "hdf5" | "h5" => Some(Self::Hdf5),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format of a file from its extension, ignoring any compression extension.
+    ///
+    /// Raw data is identified by its JSON sidecar.
+    pub fn from_filename(fname: &str) -> Option<Self> {
+        let fname = fname
+            .strip_suffix(".gz")
+            .or_else(|| fname.strip_suffix(".zst"))
+            .unwrap_or(fname);
+        match fname.rsplit_once('.')?.1 {
+            "json" => Some(Self::Raw),
+            "bin" => None,
+            extension => Self::from_string(extension),
+        }
+    }
+
+    /// The writer for this format.  The layout is ignored by formats that only have one.
+    pub fn writer(&self, layout: &Layout) -> Box<dyn OutputWriter> {
+        match self {
+            Self::Dat => Box::new(DatWriter),
+            Self::Raw => Box::new(RawWriter),
+            Self::Csv => Box::new(CsvWriter {
+                delimiter: ',',
+                layout: layout.clone(),
+            }),
+            Self::Tsv => Box::new(CsvWriter {
+                delimiter: '\t',
+                layout: layout.clone(),
+            }),
+            Self::Jsonl => Box::new(JsonlWriter {
+                layout: layout.clone(),
+            }),
+            Self::Npz => Box::new(NpzWriter),
+            Self::Parquet => Box::new(ParquetWriter),
+            Self::Hdf5 => Box::new(Hdf5Writer),
+        }
+    }
+}
+
+/// Reads a file in any of the supported formats, returning every BPM found in it.
+pub fn read_input(fname: &str) -> Result<Acquisition> {
+    match OutputFormat::from_filename(fname) {
+        Some(OutputFormat::Dat) => read_dat(fname),
+        Some(OutputFormat::Raw) => Ok(RawData::read_from_sidecar(fname)?.to_acquisition()),
+        Some(OutputFormat::Csv) => read_combined(fname, ','),
+        Some(OutputFormat::Tsv) => read_combined(fname, '\t'),
+        Some(OutputFormat::Jsonl) => read_jsonl(fname),
+        Some(OutputFormat::Npz) => read_npz(fname),
+        Some(OutputFormat::Parquet) => read_parquet(fname),
+        Some(OutputFormat::Hdf5) => read_hdf5(fname),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Could not tell the format of '{}' from its name", fname),
+        )),
+    }
+}
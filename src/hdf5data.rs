@@ -0,0 +1,838 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{get_bpm_from_name, BpmData, TimeAxis};
+use crate::compress::{self, Compression};
+use crate::units::{Position, Positions, Units};
+use crate::writer::OutputWriter;
+use std::io::{Error, ErrorKind, Result, Write};
+
+const SIGNATURE: &[u8] = b"\x89HDF\r\n\x1a\n";
+
+/// The address HDF5 uses for nothing at all.
+const UNDEFINED: u64 = u64::MAX;
+
+/// The size of a version 0 superblock, including the symbol table entry of the root group.
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// A symbol table node holds up to `2 * LEAF_K` names, and a group B-tree node up to
+/// `2 * NODE_K` children.  These are the defaults of libhdf5.
+const LEAF_K: u16 = 4;
+const NODE_K: u16 = 16;
+
+/// The end of the free list of a local heap, as libhdf5 writes it.
+const HEAP_FREE_NULL: u64 = 1;
+
+const MSG_DATASPACE: u16 = 0x0001;
+const MSG_DATATYPE: u16 = 0x0003;
+const MSG_LAYOUT: u16 = 0x0008;
+const MSG_ATTRIBUTE: u16 = 0x000c;
+const MSG_CONTINUATION: u16 = 0x0010;
+const MSG_SYMBOL_TABLE: u16 = 0x0011;
+
+pub struct Hdf5Writer;
+
+impl OutputWriter for Hdf5Writer {
+    fn extension(&self) -> &'static str {
+        "h5"
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        write_hdf5(acq, fname, compression)
+    }
+}
+
+/// The types of the datasets and attributes written by this module.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Datatype {
+    I4,
+    I8,
+    F8,
+    /// Null-padded UTF-8 strings of a fixed length in bytes.
+    Str(usize),
+}
+
+impl Datatype {
+    fn size(self) -> usize {
+        match self {
+            Self::I4 => 4,
+            Self::I8 | Self::F8 => 8,
+            Self::Str(len) => len,
+        }
+    }
+
+    /// Encodes the type as a version 1 datatype message.
+    fn encode(self) -> Vec<u8> {
+        let size = (self.size() as u32).to_le_bytes();
+        match self {
+            // A little-endian signed integer.
+            Self::I4 | Self::I8 => [
+                &[0x10, 0x08, 0, 0][..],
+                &size,
+                &0u16.to_le_bytes(),
+                &(self.size() as u16 * 8).to_le_bytes(),
+            ]
+            .concat(),
+            // A little-endian IEEE 754 double: the sign in bit 63, an 11-bit exponent from
+            // bit 52 with a bias of 1023, and a 52-bit mantissa with an implied leading 1.
+            Self::F8 => [
+                &[0x11, 0x20, 63, 0][..],
+                &size,
+                &0u16.to_le_bytes(),
+                &64u16.to_le_bytes(),
+                &[52, 11, 0, 52],
+                &1023u32.to_le_bytes(),
+            ]
+            .concat(),
+            Self::Str(_) => [&[0x13, 0x11, 0, 0][..], &size].concat(),
+        }
+    }
+}
+
+/// The arrays that can be read from an HDF5 file by this module.
+#[derive(Debug, PartialEq)]
+enum Array {
+    I4(Vec<i32>),
+    I8(Vec<i64>),
+    F8(Vec<f64>),
+    Str(Vec<String>),
+}
+
+/// Writes the data of a dataset.
+type WriteData<'a> = Box<dyn Fn(&mut dyn Write) -> Result<()> + 'a>;
+
+/// A dataset to be written: its name, shape and type, and how to write its data.
+struct Dataset<'a> {
+    name: &'static str,
+    shape: Vec<u64>,
+    datatype: Datatype,
+    write: WriteData<'a>,
+}
+
+impl Dataset<'_> {
+    fn size(&self) -> u64 {
+        self.shape.iter().product::<u64>() * self.datatype.size() as u64
+    }
+}
+
+/// Writes an acquisition as an HDF5 file.
+///
+/// The file contains the datasets `t` (nanoseconds since the Unix epoch), `bpm_names`, `bpmnum`,
+/// and `x` and `y` with the shape `(number of BPMs, number of samples)`, as `write_npz` does.
+/// When the positions are converted to physical units, `x` and `y` are doubles.  The metadata
+/// header of the text formats is written as string attributes of the root group.
+pub fn write_hdf5(acq: &Acquisition, fname: &str, compression: &Compression) -> Result<()> {
+    let data = &acq.bpms;
+    // The datasets must be rectangular, so only the samples that every BPM has are written, as
+    // in `write_npz`.
+    let n = data
+        .iter()
+        .map(|bpm| bpm.x.len().min(bpm.y.len()))
+        .fold(acq.num_samples(), usize::min);
+    let names = acq.bpm_names();
+    let name_len = names.iter().map(|x| x.len()).max().unwrap_or(0).max(1);
+    let positions: Vec<_> = data.iter().map(|bpm| acq.positions(bpm)).collect();
+    let write_positions = |file: &mut dyn Write, positions: Vec<&Positions>| -> Result<()> {
+        for bpm_positions in positions {
+            let mut buf = Vec::with_capacity(n * 8);
+            for i in 0..n {
+                match bpm_positions.get(i) {
+                    Position::Raw(val) => buf.extend(val.to_le_bytes()),
+                    Position::Scaled(val) => buf.extend(val.to_le_bytes()),
+                }
+            }
+            file.write_all(&buf)?;
+        }
+        Ok(())
+    };
+    let shape = vec![data.len() as u64, n as u64];
+    let position_type = match acq.units {
+        Units::Raw => Datatype::I4,
+        _ => Datatype::F8,
+    };
+
+    // The datasets of a group are listed in order of their names.
+    let datasets = vec![
+        Dataset {
+            name: "bpm_names",
+            shape: vec![data.len() as u64],
+            datatype: Datatype::Str(name_len),
+            write: Box::new(|file| {
+                for name in &names {
+                    let mut buf = name.as_bytes().to_vec();
+                    buf.resize(name_len, 0);
+                    file.write_all(&buf)?;
+                }
+                Ok(())
+            }),
+        },
+        Dataset {
+            name: "bpmnum",
+            shape: vec![data.len() as u64],
+            datatype: Datatype::I8,
+            write: Box::new(|file| {
+                let buf: Vec<u8> = data
+                    .iter()
+                    .flat_map(|bpm| (bpm.bpmnum as i64).to_le_bytes())
+                    .collect();
+                file.write_all(&buf)
+            }),
+        },
+        Dataset {
+            name: "t",
+            shape: vec![n as u64],
+            datatype: Datatype::I8,
+            write: Box::new(|file| {
+                let buf: Vec<u8> = (0..n)
+                    .flat_map(|i| acq.ts.time_ns(i).to_le_bytes())
+                    .collect();
+                file.write_all(&buf)
+            }),
+        },
+        Dataset {
+            name: "x",
+            shape: shape.clone(),
+            datatype: position_type,
+            write: Box::new(|file| write_positions(file, positions.iter().map(|p| &p.0).collect())),
+        },
+        Dataset {
+            name: "y",
+            shape,
+            datatype: position_type,
+            write: Box::new(|file| write_positions(file, positions.iter().map(|p| &p.1).collect())),
+        },
+    ];
+    let attributes = acq.metadata();
+
+    // Everything but the data comes first.  Its size does not depend on where the data starts.
+    let data_start = encode_header(&datasets, &attributes, 0)?
+        .len()
+        .next_multiple_of(8) as u64;
+    let header = encode_header(&datasets, &attributes, data_start)?;
+    let mut file = compression.create(fname)?;
+    file.write_all(&header)?;
+    file.write_all(&vec![0; data_start as usize - header.len()])?;
+    for dataset in &datasets {
+        (dataset.write)(&mut file)?;
+        let size = dataset.size();
+        file.write_all(&vec![0; (size.next_multiple_of(8) - size) as usize])?;
+    }
+    file.finish()
+}
+
+/// Encodes the superblock, the root group and the headers of the datasets, whose data is laid
+/// out one after the other from `data_start`, each aligned to 8 bytes.
+fn encode_header(
+    datasets: &[Dataset],
+    attributes: &[(&str, String)],
+    data_start: u64,
+) -> Result<Vec<u8>> {
+    assert!(datasets.len() <= 2 * LEAF_K as usize);
+    let mut buf = vec![0; SUPERBLOCK_SIZE];
+
+    // The names of the datasets are kept in the local heap of the root group, after the empty
+    // name at offset 0.
+    let mut names = vec![0; 8];
+    let mut name_offsets = vec![];
+    for dataset in datasets {
+        name_offsets.push(names.len() as u64);
+        names.extend(dataset.name.as_bytes());
+        names.resize((names.len() + 1).next_multiple_of(8), 0);
+    }
+    let heap = buf.len() as u64;
+    buf.extend(b"HEAP\0\0\0\0");
+    buf.extend((names.len() as u64).to_le_bytes());
+    buf.extend(HEAP_FREE_NULL.to_le_bytes());
+    buf.extend((heap + 32).to_le_bytes());
+    buf.extend(&names);
+
+    let mut address = data_start;
+    let mut headers = vec![];
+    for dataset in datasets {
+        let size = dataset.size();
+        // A contiguous layout, which has no address when it is empty.
+        let mut layout = vec![3, 1];
+        layout.extend(if size == 0 { UNDEFINED } else { address }.to_le_bytes());
+        layout.extend(size.to_le_bytes());
+        address += size.next_multiple_of(8);
+        headers.push(buf.len() as u64);
+        buf.extend(object_header(&[
+            (MSG_DATASPACE, encode_dataspace(&dataset.shape)),
+            (MSG_DATATYPE, dataset.datatype.encode()),
+            (MSG_LAYOUT, layout),
+        ])?);
+    }
+    let end = address;
+
+    // The group is small enough for a B-tree with a single symbol table node.  The keys of the
+    // B-tree are the names before and after those in the node.
+    let node = buf.len() as u64;
+    buf.extend(b"SNOD\x01\0");
+    buf.extend((datasets.len() as u16).to_le_bytes());
+    for (offset, header) in name_offsets.iter().zip(&headers) {
+        buf.extend(offset.to_le_bytes());
+        buf.extend(header.to_le_bytes());
+        // No cached information, and an empty scratch pad.
+        buf.extend([0; 24]);
+    }
+    buf.resize(node as usize + 8 + 2 * LEAF_K as usize * 40, 0);
+    let btree = buf.len() as u64;
+    buf.extend(b"TREE\0\0");
+    buf.extend(1u16.to_le_bytes());
+    buf.extend(UNDEFINED.to_le_bytes());
+    buf.extend(UNDEFINED.to_le_bytes());
+    buf.extend(0u64.to_le_bytes());
+    buf.extend(node.to_le_bytes());
+    buf.extend(name_offsets.last().copied().unwrap_or(0).to_le_bytes());
+    buf.resize(btree as usize + 24 + (4 * NODE_K as usize + 1) * 8, 0);
+
+    let root = buf.len() as u64;
+    let mut messages = vec![(
+        MSG_SYMBOL_TABLE,
+        [btree, heap].map(u64::to_le_bytes).concat(),
+    )];
+    for (key, value) in attributes {
+        messages.push((MSG_ATTRIBUTE, encode_attribute(key, value)));
+    }
+    buf.extend(object_header(&messages)?);
+
+    let superblock = [
+        SIGNATURE,
+        // The versions of the superblock, free space, root group entry and shared messages,
+        // and the sizes of addresses and lengths.
+        &[0, 0, 0, 0, 0, 8, 8, 0],
+        &LEAF_K.to_le_bytes(),
+        &NODE_K.to_le_bytes(),
+        &0u32.to_le_bytes(),
+        // The base address, free space, end of file and driver information.
+        &0u64.to_le_bytes(),
+        &UNDEFINED.to_le_bytes(),
+        &end.to_le_bytes(),
+        &UNDEFINED.to_le_bytes(),
+        // The entry of the root group, caching the addresses of its B-tree and heap.
+        &0u64.to_le_bytes(),
+        &root.to_le_bytes(),
+        &1u32.to_le_bytes(),
+        &0u32.to_le_bytes(),
+        &btree.to_le_bytes(),
+        &heap.to_le_bytes(),
+    ]
+    .concat();
+    buf[..SUPERBLOCK_SIZE].copy_from_slice(&superblock);
+    Ok(buf)
+}
+
+/// Encodes a version 1 object header holding the given messages, each padded to 8 bytes.
+fn object_header(messages: &[(u16, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    for (kind, data) in messages {
+        let size = u16::try_from(data.len().next_multiple_of(8)).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "A value is too long to be written to an HDF5 header",
+            )
+        })?;
+        body.extend(kind.to_le_bytes());
+        body.extend(size.to_le_bytes());
+        body.extend([0; 4]);
+        body.extend(data);
+        body.resize(body.len() + size as usize - data.len(), 0);
+    }
+    let mut buf = vec![1, 0];
+    buf.extend((messages.len() as u16).to_le_bytes());
+    buf.extend(1u32.to_le_bytes());
+    buf.extend((body.len() as u32).to_le_bytes());
+    buf.extend([0; 4]);
+    buf.extend(body);
+    Ok(buf)
+}
+
+/// Encodes a version 1 dataspace message.  An empty shape is a scalar.
+fn encode_dataspace(shape: &[u64]) -> Vec<u8> {
+    let mut buf = vec![1, shape.len() as u8, 0, 0, 0, 0, 0, 0];
+    for dim in shape {
+        buf.extend(dim.to_le_bytes());
+    }
+    buf
+}
+
+/// Encodes a version 1 attribute message holding a string.
+fn encode_attribute(name: &str, value: &str) -> Vec<u8> {
+    let datatype = Datatype::Str(value.len().max(1)).encode();
+    let dataspace = encode_dataspace(&[]);
+    let mut buf = vec![1, 0];
+    buf.extend((name.len() as u16 + 1).to_le_bytes());
+    buf.extend((datatype.len() as u16).to_le_bytes());
+    buf.extend((dataspace.len() as u16).to_le_bytes());
+    // The name, datatype and dataspace are each padded to 8 bytes, and followed by the data.
+    for field in [&[name.as_bytes(), &[0]].concat(), &datatype, &dataspace] {
+        buf.extend(field);
+        buf.resize(buf.len().next_multiple_of(8), 0);
+    }
+    buf.extend(value.as_bytes());
+    if value.is_empty() {
+        buf.push(0);
+    }
+    buf
+}
+
+/// Reads an HDF5 file written by `write_hdf5`.
+pub fn read_hdf5(fname: &str) -> Result<Acquisition> {
+    let buf = compress::read_to_end(fname)?;
+    let file = H5File { buf: &buf };
+    let with_name = |e: Error| Error::new(e.kind(), format!("{}: {}", fname, e));
+
+    let mut members = vec![];
+    let mut metadata = vec![];
+    for (kind, msg) in file
+        .messages(file.root().map_err(with_name)?)
+        .map_err(with_name)?
+    {
+        match kind {
+            MSG_SYMBOL_TABLE => {
+                let mut fields = Fields(msg);
+                let (btree, heap) = (fields.uint(8)?, fields.uint(8)?);
+                members = file.group(btree, heap).map_err(with_name)?;
+            }
+            MSG_ATTRIBUTE => metadata.extend(decode_attribute(msg).map_err(with_name)?),
+            _ => {}
+        }
+    }
+    let dataset = |name: &str| -> Result<(Vec<u64>, Array)> {
+        let (_, address) = members.iter().find(|(x, _)| x == name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} has no dataset '{}'", fname, name),
+            )
+        })?;
+        file.dataset(*address)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}: {}", fname, name, e)))
+    };
+
+    let units = metadata
+        .iter()
+        .find(|(key, _)| key == "units")
+        .and_then(|(_, value)| Units::from_string(value));
+    let to_f64 = |vals: Vec<i32>| vals.into_iter().map(f64::from).collect::<Vec<_>>();
+    let (ts, names, x, y, units) = match (
+        dataset("t")?,
+        dataset("bpm_names")?,
+        dataset("x")?,
+        dataset("y")?,
+        units,
+    ) {
+        (
+            (_, Array::I8(ts)),
+            (_, Array::Str(names)),
+            (shape, Array::I4(x)),
+            (_, Array::I4(y)),
+            _,
+        ) if shape.len() == 2 && x.len() == y.len() => {
+            (ts, names, to_f64(x), to_f64(y), Units::Raw)
+        }
+        (
+            (_, Array::I8(ts)),
+            (_, Array::Str(names)),
+            (shape, Array::F8(x)),
+            (_, Array::F8(y)),
+            Some(units),
+        ) if shape.len() == 2 && x.len() == y.len() && units != Units::Raw => {
+            (ts, names, x, y, units)
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not contain the expected datasets", fname),
+            ))
+        }
+    };
+
+    let n = ts.len();
+    if x.len() != names.len() * n {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} does not hold {} samples of each of {} BPMs",
+                fname,
+                n,
+                names.len()
+            ),
+        ));
+    }
+    let mut acq = Acquisition::default();
+    for (i, name) in names.iter().enumerate() {
+        let (ring, bpmnum) = get_bpm_from_name(name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unknown BPM '{}'", name)))?;
+        let bpm = acq.get_or_insert_bpm(ring, bpmnum)?;
+        *bpm = BpmData {
+            bpmnum,
+            ..Default::default()
+        };
+        for j in i * n..(i + 1) * n {
+            bpm.push(units, x[j], y[j]);
+        }
+    }
+    acq.set_time_axis(TimeAxis::from_nanos(&ts));
+    acq.units = units;
+    acq.apply_metadata(&metadata);
+    Ok(acq)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn unsupported(msg: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, msg.to_string())
+}
+
+/// Little-endian fields read from the start of a slice.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err(invalid("A structure of the file is truncated"));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        Ok(self
+            .take(len)?
+            .iter()
+            .rev()
+            .fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+}
+
+/// The parts of an HDF5 file needed to read the files written by `write_hdf5`.
+struct H5File<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> H5File<'a> {
+    fn bytes(&self, address: u64, len: u64) -> Result<&'a [u8]> {
+        let end = address.saturating_add(len);
+        if end > self.buf.len() as u64 {
+            return Err(invalid("The file is truncated"));
+        }
+        Ok(&self.buf[address as usize..end as usize])
+    }
+
+    /// The address of the object header of the root group.
+    fn root(&self) -> Result<u64> {
+        let mut fields = Fields(self.buf);
+        if fields.take(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+            return Err(invalid("Not an HDF5 file"));
+        }
+        let version = fields.uint(1)?;
+        if version > 1 {
+            return Err(unsupported(
+                "Only files with a version 0 or 1 superblock can be read",
+            ));
+        }
+        fields.take(4)?;
+        if fields.uint(1)? != 8 || fields.uint(1)? != 8 {
+            return Err(unsupported(
+                "Only files with 8-byte addresses and lengths can be read",
+            ));
+        }
+        // The group K values and file consistency flags, and the K value of chunked datasets.
+        fields.take(9)?;
+        if version == 1 {
+            fields.take(4)?;
+        }
+        // The base, free space, end of file and driver addresses, and the name of the root.
+        fields.take(40)?;
+        fields.uint(8)
+    }
+
+    /// The messages of a version 1 object header, including those in continuation blocks.
+    fn messages(&self, address: u64) -> Result<Vec<(u16, &'a [u8])>> {
+        let mut prefix = Fields(self.bytes(address, 16)?);
+        if prefix.uint(1)? != 1 {
+            return Err(unsupported("Only version 1 object headers can be read"));
+        }
+        prefix.take(7)?;
+        let mut blocks = vec![(address + 16, prefix.uint(4)?)];
+        let mut seen = vec![];
+        let mut messages = vec![];
+        while let Some((address, len)) = blocks.pop() {
+            if seen.contains(&address) {
+                return Err(invalid("An object header continues into itself"));
+            }
+            seen.push(address);
+            let mut fields = Fields(self.bytes(address, len)?);
+            while fields.0.len() >= 8 {
+                let kind = fields.uint(2)? as u16;
+                let len = fields.uint(2)? as usize;
+                fields.take(4)?;
+                let data = fields.take(len)?;
+                if kind == MSG_CONTINUATION {
+                    let mut data = Fields(data);
+                    blocks.push((data.uint(8)?, data.uint(8)?));
+                } else {
+                    messages.push((kind, data));
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// The names of the members of a group and the addresses of their object headers.
+    fn group(&self, btree: u64, heap: u64) -> Result<Vec<(String, u64)>> {
+        let mut fields = Fields(self.bytes(heap, 32)?);
+        if fields.take(8)? != b"HEAP\0\0\0\0" {
+            return Err(invalid("Expected a local heap"));
+        }
+        let len = fields.uint(8)?;
+        fields.take(8)?;
+        let names = self.bytes(fields.uint(8)?, len)?;
+        let mut members = vec![];
+        self.btree_members(btree, names, 0, &mut members)?;
+        Ok(members)
+    }
+
+    fn btree_members(
+        &self,
+        address: u64,
+        names: &[u8],
+        depth: usize,
+        members: &mut Vec<(String, u64)>,
+    ) -> Result<()> {
+        let mut fields = Fields(self.bytes(address, 24)?);
+        if fields.take(5)? != b"TREE\0" || depth > 16 {
+            return Err(invalid("Expected the B-tree of a group"));
+        }
+        let level = fields.uint(1)?;
+        let entries = fields.uint(2)?;
+        let mut fields = Fields(self.bytes(address + 24, entries * 16)?);
+        for _ in 0..entries {
+            fields.take(8)?;
+            let child = fields.uint(8)?;
+            if level > 0 {
+                self.btree_members(child, names, depth + 1, members)?;
+            } else {
+                self.symbol_table_members(child, names, members)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn symbol_table_members(
+        &self,
+        address: u64,
+        names: &[u8],
+        members: &mut Vec<(String, u64)>,
+    ) -> Result<()> {
+        let mut fields = Fields(self.bytes(address, 8)?);
+        if fields.take(6)? != b"SNOD\x01\0" {
+            return Err(invalid("Expected a symbol table node"));
+        }
+        let entries = fields.uint(2)?;
+        let mut fields = Fields(self.bytes(address + 8, entries * 40)?);
+        for _ in 0..entries {
+            let offset = fields.uint(8)? as usize;
+            let header = fields.uint(8)?;
+            fields.take(24)?;
+            let name = names
+                .get(offset..)
+                .and_then(|x| x.split(|&b| b == 0).next())
+                .ok_or_else(|| invalid("The name of a dataset is outside its heap"))?;
+            members.push((String::from_utf8_lossy(name).to_string(), header));
+        }
+        Ok(())
+    }
+
+    /// The shape and data of a dataset.
+    fn dataset(&self, address: u64) -> Result<(Vec<u64>, Array)> {
+        let (mut shape, mut datatype, mut data) = (None, None, None);
+        for (kind, msg) in self.messages(address)? {
+            match kind {
+                MSG_DATASPACE => shape = Some(decode_dataspace(msg)?),
+                MSG_DATATYPE => datatype = Some(decode_datatype(msg)?),
+                MSG_LAYOUT => data = Some(self.layout_data(msg)?),
+                _ => {}
+            }
+        }
+        match (shape, datatype, data) {
+            (Some(shape), Some(datatype), Some(data)) => {
+                let array = decode_array(datatype, data, shape.iter().product())?;
+                Ok((shape, array))
+            }
+            _ => Err(invalid("Not a dataset")),
+        }
+    }
+
+    /// The data of a dataset stored contiguously or in its header.
+    fn layout_data(&self, msg: &'a [u8]) -> Result<&'a [u8]> {
+        let mut fields = Fields(msg);
+        match (fields.uint(1)?, fields.uint(1)?) {
+            (3, 0) => {
+                let len = fields.uint(2)? as usize;
+                fields.take(len)
+            }
+            (3, 1) => match (fields.uint(8)?, fields.uint(8)?) {
+                (UNDEFINED, _) => Ok(&[]),
+                (address, len) => self.bytes(address, len),
+            },
+            _ => Err(unsupported(
+                "Only datasets that are stored contiguously can be read",
+            )),
+        }
+    }
+}
+
+fn decode_dataspace(msg: &[u8]) -> Result<Vec<u64>> {
+    let mut fields = Fields(msg);
+    let version = fields.uint(1)?;
+    let rank = fields.uint(1)? as usize;
+    match version {
+        1 => fields.take(6)?,
+        2 => fields.take(2)?,
+        _ => return Err(unsupported("Unknown dataspace version")),
+    };
+    (0..rank).map(|_| fields.uint(8)).collect()
+}
+
+fn decode_datatype(msg: &[u8]) -> Result<Datatype> {
+    let mut fields = Fields(msg);
+    let class = fields.uint(1)? & 0x0f;
+    let bits = fields.uint(3)?;
+    let size = fields.uint(4)?;
+    match (class, size) {
+        // Little-endian integers and floats.
+        (0, 4) if bits & 1 == 0 => Ok(Datatype::I4),
+        (0, 8) if bits & 1 == 0 => Ok(Datatype::I8),
+        (1, 8) if bits & 1 == 0 => Ok(Datatype::F8),
+        (3, len) => Ok(Datatype::Str(len as usize)),
+        _ => Err(unsupported("Unsupported datatype")),
+    }
+}
+
+fn decode_array(datatype: Datatype, data: &[u8], count: u64) -> Result<Array> {
+    let size = datatype.size();
+    if size == 0 || (data.len() / size) < count as usize {
+        return Err(invalid("The data is shorter than the dataset"));
+    }
+    let elements = data.chunks_exact(size).take(count as usize);
+    Ok(match datatype {
+        Datatype::I4 => Array::I4(
+            elements
+                .map(|x| i32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        Datatype::I8 => Array::I8(
+            elements
+                .map(|x| i64::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        Datatype::F8 => Array::F8(
+            elements
+                .map(|x| f64::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        Datatype::Str(_) => Array::Str(elements.map(decode_string).collect()),
+    })
+}
+
+/// Decodes a null-padded or null-terminated string.
+fn decode_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).to_string()
+}
+
+/// Decodes an attribute holding a single string, skipping any others.
+fn decode_attribute(msg: &[u8]) -> Result<Option<(String, String)>> {
+    let mut fields = Fields(msg);
+    let version = fields.uint(1)?;
+    if !(1..=3).contains(&version) {
+        return Ok(None);
+    }
+    fields.take(1)?;
+    let name_len = fields.uint(2)? as usize;
+    let datatype_len = fields.uint(2)? as usize;
+    let dataspace_len = fields.uint(2)? as usize;
+    if version == 3 {
+        fields.take(1)?;
+    }
+    // The fields of version 1 attributes are padded to 8 bytes.
+    let mut field = |len: usize| {
+        let padded = if version == 1 {
+            len.next_multiple_of(8)
+        } else {
+            len
+        };
+        fields.take(padded).map(|x| &x[..len])
+    };
+    let name = decode_string(field(name_len)?);
+    let datatype = decode_datatype(field(datatype_len)?);
+    let shape = decode_dataspace(field(dataspace_len)?)?;
+    match datatype {
+        Ok(Datatype::Str(len)) if shape.is_empty() => {
+            Ok(Some((name, decode_string(fields.take(len)?))))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+
+    #[test]
+    fn datatypes_are_encoded_as_libhdf5_encodes_them() {
+        // H5T_STD_I32LE, H5T_IEEE_F64LE and a null-padded UTF-8 string of 19 bytes.
+        assert_eq!(
+            Datatype::I4.encode(),
+            b"\x10\x08\0\0\x04\0\0\0\0\0\x20\0".to_vec()
+        );
+        assert_eq!(
+            Datatype::F8.encode(),
+            b"\x11\x20\x3f\0\x08\0\0\0\0\0\x40\0\x34\x0b\0\x34\xff\x03\0\0".to_vec()
+        );
+        assert_eq!(
+            Datatype::Str(19).encode(),
+            b"\x13\x11\0\0\x13\0\0\0".to_vec()
+        );
+    }
+
+    #[test]
+    fn hdf5_round_trip() {
+        for (name, acq) in acquisitions() {
+            let read = write_and_read(&acq, &format!("{}.h5", name), write_hdf5, read_hdf5);
+            assert_same_data(&read, &acq);
+            assert_same_metadata(&read, &acq);
+        }
+    }
+
+    #[test]
+    fn the_superblock_gives_the_length_of_the_file() {
+        let (_, acq) = acquisitions().remove(0);
+        let fname = temp_file("length.h5");
+        write_hdf5(&acq, &fname, &Compression::None).unwrap();
+        let buf = std::fs::read(&fname).unwrap();
+        std::fs::remove_file(&fname).unwrap();
+        assert_eq!(&buf[..8], SIGNATURE);
+        assert_eq!(Fields(&buf[40..48]).uint(8).unwrap(), buf.len() as u64);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let fname = temp_file("not.h5");
+        std::fs::write(&fname, b"\x89HDF\r\n\x1a\n\x02").unwrap();
+        let truncated = read_hdf5(&fname);
+        std::fs::write(&fname, b"not an HDF5 file").unwrap();
+        let other = read_hdf5(&fname);
+        std::fs::remove_file(&fname).unwrap();
+        assert_eq!(truncated.unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(other.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
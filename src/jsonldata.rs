@@ -0,0 +1,231 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{get_bpm_from_name, BpmData, TimeAxis};
+use crate::compress::{self, Compression};
+use crate::csvdata::Layout;
+use crate::units::Units;
+use crate::writer::OutputWriter;
+use serde_json::{json, Value};
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+
+/// Writes the whole acquisition as JSON Lines.
+pub struct JsonlWriter {
+    pub layout: Layout,
+}
+
+impl OutputWriter for JsonlWriter {
+    fn extension(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        write_jsonl(acq, fname, &self.layout, compression)
+    }
+}
+
+/// Writes an acquisition as JSON Lines.
+///
+/// With the wide layout there is one object per sample, of the form
+/// `{"t": ..., "x": {"<bpm>": ..., ...}, "y": {"<bpm>": ..., ...}}`.  With the long layout there
+/// is one object per BPM, of the form `{"bpm": ..., "bpmnum": ..., "t": [...], "x": [...],
+/// "y": [...]}`.  The first line is an object of the form `{"metadata": {...}}` describing the
+/// acquisition.  When the positions are converted to physical units, every object also has a
+/// `"units"` key.
+pub fn write_jsonl(
+    acq: &Acquisition,
+    fname: &str,
+    layout: &Layout,
+    compression: &Compression,
+) -> Result<()> {
+    let mut file = compression.create(fname)?;
+    write_metadata(&mut file, &acq.metadata())?;
+    write_records(&mut file, acq, layout)?;
+    file.finish()
+}
+
+/// Writes the `{"metadata": {...}}` line that starts a JSON Lines file.
+pub fn write_metadata(file: &mut impl Write, metadata: &[(&str, String)]) -> Result<()> {
+    let metadata: serde_json::Map<String, Value> = metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), json!(value)))
+        .collect();
+    writeln!(file, "{}", json!({ "metadata": metadata }))
+}
+
+/// Writes the objects holding the positions of an acquisition, in either layout.
+pub fn write_records(file: &mut impl Write, acq: &Acquisition, layout: &Layout) -> Result<()> {
+    let names: Vec<String> = acq
+        .bpm_names()
+        .iter()
+        .map(|name| serde_json::to_string(name).unwrap())
+        .collect();
+    let positions: Vec<_> = acq.bpms.iter().map(|bpm| acq.positions(bpm)).collect();
+
+    match layout {
+        Layout::Wide => {
+            let units = match acq.units.name() {
+                Some(units) => format!(",\"units\":\"{}\"", units),
+                None => String::new(),
+            };
+            for (i, t) in acq.ts.formatted().enumerate() {
+                write!(file, "{{\"t\":\"{}\"{},\"x\":{{", t, units)?;
+                for (j, ((x, _), name)) in positions.iter().zip(&names).enumerate() {
+                    let sep = if j == 0 { "" } else { "," };
+                    write!(file, "{sep}{name}:{}", x.get(i))?;
+                }
+                write!(file, "}},\"y\":{{")?;
+                for (j, ((_, y), name)) in positions.iter().zip(&names).enumerate() {
+                    let sep = if j == 0 { "" } else { "," };
+                    write!(file, "{sep}{name}:{}", y.get(i))?;
+                }
+                writeln!(file, "}}}}")?;
+            }
+        }
+        Layout::Long => {
+            let ts: Vec<String> = acq.ts.formatted().collect();
+            for (bpm, (x, y)) in acq.bpms.iter().zip(&positions) {
+                let n = ts.len().min(x.len()).min(y.len());
+                let mut block = json!({
+                    "bpm": acq.bpm_name(bpm),
+                    "bpmnum": bpm.bpmnum,
+                    "t": ts[..n],
+                    "x": (0..n).map(|i| x.get(i)).collect::<Vec<_>>(),
+                    "y": (0..n).map(|i| y.get(i)).collect::<Vec<_>>(),
+                });
+                if let Some(units) = acq.units.name() {
+                    block["units"] = json!(units);
+                }
+                writeln!(file, "{}", block)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads JSON Lines written by `write_jsonl`, in either layout.
+pub fn read_jsonl(fname: &str) -> Result<Acquisition> {
+    let invalid = |line: usize, msg: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}:{}: {}", fname, line + 1, msg),
+        )
+    };
+    let mut acq = Acquisition::default();
+    let mut ts: Vec<String> = vec![];
+    // The line of each sample, for errors about its time.
+    let mut ts_lines = vec![];
+    let mut metadata = vec![];
+
+    for (i, line) in compress::open(fname)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let obj: Value = serde_json::from_str(&line)?;
+        if let Some(header) = obj["metadata"].as_object() {
+            metadata = header
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect();
+            continue;
+        }
+        let units = match obj["units"].as_str() {
+            Some(units) => Units::from_string(units).ok_or_else(|| invalid(i, "Unknown units"))?,
+            None => Units::Raw,
+        };
+        acq.units = units;
+        let position = |val: Option<&Value>| {
+            let val = match units {
+                Units::Raw => val
+                    .and_then(|x| x.as_i64())
+                    .and_then(|x| i32::try_from(x).ok())
+                    .map(f64::from),
+                _ => val.and_then(|x| x.as_f64()),
+            };
+            val.ok_or_else(|| invalid(i, "Could not parse a position"))
+        };
+        let positions = |val: &Value| -> Result<Vec<f64>> {
+            val.as_array()
+                .ok_or_else(|| invalid(i, "Expected an array of positions"))?
+                .iter()
+                .map(|x| position(Some(x)))
+                .collect()
+        };
+
+        if let Some(name) = obj["bpm"].as_str() {
+            let (ring, bpmnum) = get_bpm_from_name(name)
+                .ok_or_else(|| invalid(i, format!("Unknown BPM '{}'", name).as_str()))?;
+            if acq.bpms.is_empty() {
+                ts = serde_json::from_value(obj["t"].clone())?;
+                ts_lines = vec![i; ts.len()];
+            }
+            let bpm = acq
+                .get_or_insert_bpm(ring, bpmnum)
+                .map_err(|e| invalid(i, &e.to_string()))?;
+            let (x, y) = (positions(&obj["x"])?, positions(&obj["y"])?);
+            if x.len() != y.len() {
+                return Err(invalid(i, "The x and y positions differ in number"));
+            }
+            *bpm = BpmData {
+                bpmnum,
+                ..Default::default()
+            };
+            for (x, y) in x.into_iter().zip(y) {
+                bpm.push(units, x, y);
+            }
+            continue;
+        }
+
+        let (t, x, y) = match (
+            obj["t"].as_str(),
+            obj["x"].as_object(),
+            obj["y"].as_object(),
+        ) {
+            (Some(t), Some(x), Some(y)) => (t, x, y),
+            _ => return Err(invalid(i, "Expected either a sample or a BPM block")),
+        };
+        ts.push(t.to_string());
+        ts_lines.push(i);
+        for (name, x_val) in x {
+            let (ring, bpmnum) = get_bpm_from_name(name)
+                .ok_or_else(|| invalid(i, format!("Unknown BPM '{}'", name).as_str()))?;
+            let bpm = acq
+                .get_or_insert_bpm(ring, bpmnum)
+                .map_err(|e| invalid(i, &e.to_string()))?;
+            let (x, y) = (position(Some(x_val))?, position(y.get(name))?);
+            bpm.push(units, x, y);
+        }
+    }
+    acq.bpms.sort_by_key(|bpm| bpm.bpmnum);
+    acq.set_time_axis(
+        TimeAxis::from_strings(&ts).map_err(|(index, e)| invalid(ts_lines[index], &e))?,
+    );
+    acq.apply_metadata(&metadata);
+    Ok(acq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+
+    #[test]
+    fn jsonl_round_trip() {
+        for (name, acq) in acquisitions() {
+            for layout in [Layout::Wide, Layout::Long] {
+                let read = write_and_read(
+                    &acq,
+                    &format!("{}-{:?}.jsonl", name, layout),
+                    |acq, fname, compression| write_jsonl(acq, fname, &layout, compression),
+                    read_jsonl,
+                );
+                assert_same_data(&read, &acq);
+                assert_same_metadata(&read, &acq);
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::prelude::*;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default, PartialEq, Debug, Clone)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `message` and `fields` keys.
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_string(arg: &str) -> Option<Self> {
+        match arg.to_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_log_format(format: &LogFormat) {
+    JSON_LOGS.store(*format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn json_logs() -> bool {
+    JSON_LOGS.load(Ordering::Relaxed)
+}
+
+pub fn print_log_message(msg: &str) {
+    print_log_event("info", msg, vec![]);
+}
+
+pub fn print_log_warning(msg: &str) {
+    print_log_event("warning", msg, vec![]);
+}
+
+pub fn print_log_error(msg: &str) {
+    print_log_event("error", msg, vec![]);
+}
+
+/// Writes a log message to stderr.
+///
+/// In the text format, the fields are not shown, so anything important should also be included
+/// in the message itself.
+pub fn print_log_event(level: &str, msg: &str, fields: Vec<(&str, Value)>) {
+    let timestamp = Local::now().timestamp_millis();
+    if json_logs() {
+        let mut event = Map::new();
+        event.insert("timestamp".to_string(), Value::from(timestamp));
+        event.insert("level".to_string(), Value::from(level));
+        event.insert("message".to_string(), Value::from(msg));
+        event.insert(
+            "fields".to_string(),
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            ),
+        );
+        eprintln!("{}", Value::Object(event));
+    } else if level == "info" {
+        eprintln!("{}: {}", timestamp, msg);
+    } else {
+        eprintln!("{}", msg);
+    }
+}
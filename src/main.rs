@@ -1,99 +1,203 @@
+use crate::acquisition::{Acquisition, Request};
 use crate::bpmdata::get_bpm_number;
-use crate::bpmdata::{BpmData, Ring};
-use chrono::offset::TimeZone;
+use crate::bpmdata::{get_bpm_cell, get_bpm_name, DatWriter, Ring};
+use crate::cli::{
+    legacy_args, Cli, Command, ConvertArgs, GetArgs, ListCommand, LiveArgs, RequestSpan,
+};
+use crate::compress::{set_overwrite, with_overwrite, Compression, Overwrite, STDOUT};
+use crate::config::{archiver_address, ring_names, set_address_override, set_config, Config};
+use crate::csvdata::Layout;
+use crate::estimate::{format_size, SizeEstimate};
+use crate::formats::{read_input, OutputFormat};
+use crate::logging::*;
+use crate::manifest::{write_manifest, Manifest};
+use crate::rawdata::RawData;
+use crate::timespec::check_time_range;
+use crate::timezone::{in_timezone, set_timezone};
+use crate::units::{Calibration, Units};
+use crate::writer::{output_paths, write_output, WrittenFile};
 use chrono::prelude::*;
 use chrono::Duration;
+use clap::{CommandFactory, FromArgMatches};
 use itertools::Itertools;
-use std::collections::VecDeque;
-use std::convert::TryInto;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::env::args;
-use std::io::{BufReader, Read, Result, Write};
+use std::io::{stdout, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
 use std::process::exit;
-use threadpool::ThreadPool;
 
+mod acquisition;
 mod bpmdata;
+mod cli;
+mod compress;
+mod config;
+mod csvdata;
+mod estimate;
+mod formats;
+mod hdf5data;
+mod jsonldata;
+mod logging;
+mod manifest;
+mod npzdata;
+mod parquetdata;
+mod rawdata;
+mod template;
+mod timespec;
+mod timezone;
+mod units;
+mod writer;
 
 static VERSION_NUMBER: &str = "1.2";
 
-#[derive(Default)]
+/// The sample rate of the archiver, for when it cannot be asked (e.g., with `--dry-run`).
+const NOMINAL_FS: f64 = 10139.0;
+
+const CALIBRATED_RAW_UNITS: &str =
+    "A calibration cannot be applied to positions written in the archiver's own units (--units raw)";
+
+/// Where a request to the archiver stops.
+#[derive(Clone, Copy, Debug)]
+enum RequestEnd {
+    Time(DateTime<FixedOffset>),
+    Samples(u64),
+}
+
 struct FastArchiverOptions {
-    start_time: Option<DateTime<Local>>,
-    end_time: Option<DateTime<Local>>,
+    start_time: DateTime<FixedOffset>,
+    end: RequestEnd,
     deci: bool,
     file: String,
     ring: Ring,
     find_dump: bool,
+    format: OutputFormat,
+    layout: Layout,
+    compression: Compression,
+    units: Units,
+    calibration: Calibration,
+    overwrite: Overwrite,
+    size_limit: u64,
+    yes: bool,
+    dry_run: bool,
+    check_bpms: bool,
     bpm_search_terms: Vec<String>,
 }
 
 impl FastArchiverOptions {
-    fn build_options(mut args_list: VecDeque<String>) -> Self {
-        let mut opts: Self = Self::default();
-        opts.file = "fa_data".to_string();
-        while !args_list.is_empty() {
-            let next_arg = args_list.pop_front().unwrap();
-            match next_arg.as_str() {
-                "--start" => match args_list.pop_front() {
-                    Some(expr) => opts.start_time = get_time_from_string(expr),
-                    None => {
-                        print_error_and_exit("Input parameters after `--start` are incorrect.");
-                    }
-                },
-                "--end" => match args_list.pop_front() {
-                    Some(expr) => opts.end_time = get_time_from_string(expr),
-                    None => {
-                        print_error_and_exit("Input parameters after `--end` are incorrect.");
-                    }
-                },
-                "--ring" => match args_list.pop_front() {
-                    Some(expr) => {
-                        opts.ring = match expr.to_lowercase().as_str() {
-                            "r1" => Ring::R1,
-                            "r3" => Ring::R3,
-                            _ => Ring::Unk,
-                        }
-                    }
-                    None => {
-                        print_error_and_exit("Input parameters after `--ring` are incorrect.");
-                    }
-                },
-                "--file" => match args_list.pop_front() {
-                    Some(expr) => opts.file = expr,
-                    None => {
-                        print_error_and_exit("Input parameters after `--file` are incorrect.");
-                    }
-                },
-                "--deci" => opts.deci = true,
-                "--find_dump" => opts.find_dump = true,
-                expr => {
-                    opts.bpm_search_terms.push(expr.to_string());
+    fn from_args(args: GetArgs, find_dump: bool) -> Self {
+        let overwrite = args.output.overwrite();
+        let calibration = args.output.calibration.unwrap_or_default();
+        let span = match args.request.span() {
+            Ok(span) => span,
+            Err(e) => print_error_and_exit(&e),
+        };
+        let (start_time, end) = match span {
+            RequestSpan::Range(start, end) => (start, RequestEnd::Time(end)),
+            RequestSpan::Samples(start, samples) => (start, RequestEnd::Samples(samples)),
+            RequestSpan::Around { at, before, after } => {
+                // The sample rate is needed to find where to start.
+                let fs = sample_rate(&args.request.ring, args.dry_run);
+                let decimation = if args.deci { 64.0 } else { 1.0 };
+                let before_ns = before as f64 * decimation * 1e9 / fs;
+                let start = Some(before_ns)
+                    .filter(|x| *x < i64::MAX as f64)
+                    .and_then(|x| at.checked_sub_signed(Duration::nanoseconds(x.round() as i64)))
+                    .ok_or_else(|| "is too far back".to_string())
+                    .and_then(|x| check_time_range(in_timezone(x)));
+                match start {
+                    Ok(start) => (start, RequestEnd::Samples(before.saturating_add(after))),
+                    Err(e) => print_error_and_exit(
+                        format!("Invalid --before-samples: the start time {e}").as_str(),
+                    ),
                 }
             }
+        };
+        // Calibrated positions are no longer integers, so they are written in nm by default.
+        let units = match args.output.units {
+            Some(units) => units,
+            None if !calibration.is_empty() => Units::Nm,
+            None => Units::Raw,
+        };
+        let opts = Self {
+            start_time,
+            end,
+            deci: args.deci,
+            file: args.output.output,
+            ring: args.request.ring,
+            find_dump,
+            format: args.output.format,
+            layout: args.output.layout,
+            compression: args.output.compress,
+            units,
+            calibration,
+            overwrite,
+            size_limit: args.size_limit,
+            yes: args.yes,
+            dry_run: args.dry_run,
+            check_bpms: args.check_bpms,
+            bpm_search_terms: args.request.bpms,
+        };
+        set_overwrite(opts.overwrite);
+        if json_logs() {
+            print_log_event("info", "Options", opts.log_fields());
+        } else {
+            eprintln!("{}", opts.log_string().as_str());
         }
-        println!("{}", opts.log_string().as_str());
         opts
     }
 
+    fn log_fields(&self) -> Vec<(&str, Value)> {
+        vec![
+            ("start_time", json!(self.start_time)),
+            ("end_time", json!(self.end_time())),
+            (
+                "duration_ms",
+                json!(self
+                    .end_time()
+                    .map(|end| (end - self.start_time).num_milliseconds())),
+            ),
+            ("samples", json!(self.samples())),
+            ("decimated", json!(self.deci)),
+            ("basename", json!(self.file)),
+            ("format", json!(format!("{:?}", self.format))),
+            ("layout", json!(format!("{:?}", self.layout))),
+            ("compression", json!(format!("{:?}", self.compression))),
+            ("units", json!(format!("{:?}", self.units))),
+            ("overwrite", json!(format!("{:?}", self.overwrite))),
+            ("size_limit_bytes", json!(self.size_limit)),
+            ("ring", json!(self.ring.to_string())),
+            ("find_dump", json!(self.find_dump)),
+            ("dry_run", json!(self.dry_run)),
+            ("check_bpms", json!(self.check_bpms)),
+            ("bpm_search_terms", json!(self.bpm_search_terms)),
+        ]
+    }
+
     fn log_string(&self) -> String {
         let timestamp = Local::now().timestamp_millis();
-        let start_str = format!("{}: Start time: {:?}", timestamp, self.start_time.unwrap());
-        let end_str = format!("{}: End time: {:?}", timestamp, self.end_time.unwrap());
-        let duration_str = format!(
-            "{}: Duration: {:?} ms",
-            timestamp,
-            (self.end_time.unwrap() - self.start_time.unwrap()).num_milliseconds()
-        );
+        let start_str = format!("{}: Start time: {:?}", timestamp, self.start_time);
+        let end_str = match self.end {
+            RequestEnd::Time(end) => format!(
+                "{}: End time: {:?}\n{}: Duration: {:?} ms",
+                timestamp,
+                end,
+                timestamp,
+                (end - self.start_time).num_milliseconds()
+            ),
+            RequestEnd::Samples(samples) => {
+                format!("{}: Requesting {} samples", timestamp, samples)
+            }
+        };
         let deci_str = if self.deci {
             format!("{}: Returning decimated data", timestamp)
         } else {
             format!("{}: Returning full data.", timestamp)
         };
-        let filename_str = format!("{}: Basename for files is '{}'", timestamp, self.file);
-        let ring_str = match self.ring {
-            Ring::R1 => format!("{}: Acquiring data for R1", timestamp),
-            Ring::R3 => format!("{}: Acquiring data for R3", timestamp),
-            Ring::Unk => format!("{}: Acquiring data for UNKNOWN", timestamp),
-        };
+        let filename_str = format!(
+            "{}: Basename for files is '{}' ({:?} format, {:?} compression, {:?} units)",
+            timestamp, self.file, self.format, self.compression, self.units
+        );
+        let ring_str = format!("{}: Acquiring data for {}", timestamp, self.ring);
         let find_dump_str = if self.find_dump {
             format!("{}: Searching for dump events.", timestamp)
         } else {
@@ -108,76 +212,77 @@ impl FastArchiverOptions {
             )
         };
         format!(
-            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n========================================",
-            start_str,
-            end_str,
-            duration_str,
-            deci_str,
-            filename_str,
-            ring_str,
-            find_dump_str,
-            search_term_str
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n========================================",
+            start_str, end_str, deci_str, filename_str, ring_str, find_dump_str, search_term_str
         )
     }
 
+    fn end_time(&self) -> Option<DateTime<FixedOffset>> {
+        match self.end {
+            RequestEnd::Time(end) => Some(end),
+            RequestEnd::Samples(_) => None,
+        }
+    }
+
+    fn samples(&self) -> Option<u64> {
+        match self.end {
+            RequestEnd::Time(_) => None,
+            RequestEnd::Samples(samples) => Some(samples),
+        }
+    }
+
     fn check_options(&self) -> bool {
         let mut result: bool = true;
-        if self.start_time.is_none() {
-            eprintln!("No start time was given");
-            result = false;
+        match self.end {
+            RequestEnd::Time(end) if end <= self.start_time => {
+                print_log_error("The end time must be after the start time");
+                result = false;
+            }
+            RequestEnd::Samples(0) => {
+                print_log_error("At least one sample must be requested");
+                result = false;
+            }
+            _ => {}
         }
-        if self.end_time.is_none() {
-            eprintln!("No end time was given");
+        if self.format == OutputFormat::Raw && self.units != Units::Raw {
+            print_log_error("Raw data can only be written in the archiver's own units");
             result = false;
         }
-        if self.ring == Ring::Unk {
-            eprintln!("No ring variable was given");
+        if self.units == Units::Raw && !self.calibration.is_empty() {
+            print_log_error(CALIBRATED_RAW_UNITS);
             result = false;
         }
         result
     }
 }
 
-fn print_log_message(msg: &str) {
-    println!("{}: {}", Local::now().timestamp_millis(), msg);
-}
-
-fn get_time_from_string(arg: String) -> Option<DateTime<Local>> {
-    match Local.datetime_from_str(&arg, "%Y-%m-%dT%H:%M:%S%.f") {
-        Ok(dt) => Some(dt),
-        _ => None,
-    }
-}
-
-fn print_error_and_exit(err: &str) {
-    eprintln!("{}", err);
+fn print_error_and_exit(err: &str) -> ! {
+    print_log_error(err);
     exit(1);
 }
 
 fn root_mean_square(vec: &[i32]) -> f32 {
     let sum_squares = vec.iter().fold(0, |acc, &x| acc + (x as i64).pow(2));
-    return ((sum_squares as f32) / (vec.len() as f32)).sqrt();
+    ((sum_squares as f32) / (vec.len() as f32)).sqrt()
 }
 
-fn get_fs(ring: Ring) -> Result<f64> {
-    const HOST: &str = "fa";
-    let port: u16 = match ring {
-        Ring::R1 => 12001,
-        Ring::R3 => 32001,
-        Ring::Unk => {
-            unreachable!("Should be impossible to get here...");
-        }
-    };
-    let cmd = "CF\n";
+/// Asks the archiver for part of its configuration, such as `F` for its sample rate.
+fn get_archiver_config(ring: &Ring, what: &str) -> Result<String> {
+    let (host, port) = archiver_address(ring);
+    let cmd = format!("C{}\n", what);
 
     let mut buf = Vec::new();
 
-    let mut stream = std::net::TcpStream::connect((HOST, port))?;
+    let mut stream = std::net::TcpStream::connect((host.as_str(), port))?;
 
     stream.write_all(cmd.as_bytes())?;
     stream.read_to_end(&mut buf)?;
 
-    let info = std::str::from_utf8(&buf).unwrap();
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn get_fs(ring: Ring) -> Result<f64> {
+    let info = get_archiver_config(&ring, "F")?;
 
     let infvec: Vec<&str> = info.split('\n').collect();
     let fs = infvec[0].parse::<f64>().unwrap();
@@ -185,268 +290,1031 @@ fn get_fs(ring: Ring) -> Result<f64> {
     Ok(fs)
 }
 
-fn get_archived_data(
-    ring: Ring,
-    start_dt: &DateTime<Local>,
-    end_dt: &DateTime<Local>,
-    bpm_search_term: &Vec<String>,
+/// Asks the archiver for the BPMs it stores, by number.
+fn get_bpm_list(ring: &Ring) -> Result<BTreeMap<usize, String>> {
+    let info = get_archiver_config(ring, "L")?;
+
+    // Each line holds the number of a BPM and its name.
+    let mut bpms = BTreeMap::new();
+    for line in info.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(bpmnum), Some(name)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let bpmnum = bpmnum.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not understand the BPM list line '{}'", line),
+            )
+        })?;
+        bpms.insert(bpmnum, name.to_string());
+    }
+    Ok(bpms)
+}
+
+/// Compares the names of the BPMs in the ring definitions with those stored by the archiver,
+/// warning about every difference, and refuses to go on if any of the BPMs to acquire would be
+/// written under the wrong name.
+fn check_bpm_names(ring: &Ring, bpm_range: &[usize]) {
+    let archived = match get_bpm_list(ring) {
+        Ok(bpms) => bpms,
+        Err(e) => print_error_and_exit(
+            format!("Could not ask the archiver for its list of BPMs: {e}").as_str(),
+        ),
+    };
+    let defined = ring.bpm_names();
+    let last = archived
+        .keys()
+        .last()
+        .copied()
+        .unwrap_or_default()
+        .max(defined.len());
+
+    let mut differences = 0;
+    let mut misnamed = vec![];
+    for bpmnum in 1..=last {
+        let message = match (defined.get(bpmnum - 1), archived.get(&bpmnum)) {
+            (Some(ours), Some(theirs)) if ours == theirs => continue,
+            (None, None) => continue,
+            (Some(ours), Some(theirs)) => format!(
+                "BPM {} is {} in the ring definitions, but {} in the archiver",
+                bpmnum, ours, theirs
+            ),
+            (Some(ours), None) => format!(
+                "BPM {} ({}) is in the ring definitions, but not in the archiver",
+                bpmnum, ours
+            ),
+            (None, Some(theirs)) => format!(
+                "BPM {} ({}) is in the archiver, but not in the ring definitions",
+                bpmnum, theirs
+            ),
+        };
+        print_log_event(
+            "warning",
+            message.as_str(),
+            vec![
+                ("bpmnum", json!(bpmnum)),
+                ("defined", json!(defined.get(bpmnum - 1))),
+                ("archived", json!(archived.get(&bpmnum))),
+            ],
+        );
+        differences += 1;
+        if bpm_range.contains(&bpmnum) {
+            misnamed.push(bpmnum);
+        }
+    }
+
+    if differences == 0 {
+        print_log_message(
+            format!(
+                "The names of all {} BPMs match those in the archiver",
+                defined.len()
+            )
+            .as_str(),
+        );
+    } else if misnamed.is_empty() {
+        print_log_warning(
+            format!(
+                "{} BPMs differ from those in the archiver, but none of them are being acquired",
+                differences
+            )
+            .as_str(),
+        );
+    } else {
+        print_error_and_exit(
+            format!(
+                "The names of BPMs {:?} differ from those in the archiver, so their data would be \
+                 written under the wrong names. Update the ring definitions (see the README)",
+                misnamed
+            )
+            .as_str(),
+        );
+    }
+}
+
+/// Asks the archiver for its sample rate, or assumes the nominal one for a dry run.
+fn sample_rate(ring: &Ring, dry_run: bool) -> f64 {
+    if dry_run {
+        return NOMINAL_FS;
+    }
+    match get_fs(ring.clone()) {
+        Ok(result) => result,
+        Err(e) => {
+            let (host, port) = archiver_address(ring);
+            print_error_and_exit(
+                format!(
+                    "Could not ask the archiver at {}:{} for its sample rate: {e}",
+                    host, port
+                )
+                .as_str(),
+            );
+        }
+    }
+}
+
+/// The numbers of the BPMs to acquire, counting from 1 as the archiver does.
+fn bpms_to_acquire(ring: &Ring, bpm_search_term: &Vec<String>) -> Vec<usize> {
+    if bpm_search_term.is_empty() {
+        return (1..=ring.bpm_names().len()).collect();
+    }
+    // Search terms may overlap, such as a beamline and the name of one of its BPMs, but each BPM
+    // is only acquired once, in the order of the archiver.
+    match get_bpm_number(bpm_search_term, ring) {
+        Some(ans) => ans.into_iter().sorted().dedup().collect(),
+        None => {
+            print_error_and_exit(format!("No BPMs found matching {:?}", bpm_search_term).as_str());
+        }
+    }
+}
+
+/// The BPMs matched by more than one of the search terms, with the terms that match each.
+fn overlapping_search_terms<'a>(ring: &Ring, terms: &'a [String]) -> Vec<(usize, Vec<&'a str>)> {
+    let mut matches: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for term in terms {
+        for bpmnum in get_bpm_number(&vec![term.clone()], ring).unwrap_or_default() {
+            matches.entry(bpmnum).or_default().push(term);
+        }
+    }
+    matches
+        .into_iter()
+        .filter(|(_, terms)| terms.len() > 1)
+        .collect()
+}
+
+/// The number of samples that the archiver should return for a request.
+fn expected_samples(
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
+    fs: f64,
+    decimation: usize,
+) -> u64 {
+    match end {
+        RequestEnd::Time(end_dt) => {
+            let length_ns = (end_dt - *start_dt).num_nanoseconds().unwrap_or(i64::MAX);
+            (length_ns.max(0) as f64 * fs / (1e9 * decimation as f64)).ceil() as u64
+        }
+        RequestEnd::Samples(samples) => samples,
+    }
+}
+
+/// Estimates the size of a request before it is made, refusing it if it is over `--size-limit`
+/// unless `--yes` was given.
+fn check_size(
+    opts: &FastArchiverOptions,
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
     decimated: bool,
-) -> Result<Vec<BpmData>> {
-    const HOST: &str = "fa";
-    let port: u16;
-    let mut bpm_range: Vec<String>;
-    let bpm_cmd_str: String;
+    format: &OutputFormat,
+) {
+    let fs = sample_rate(&opts.ring, false);
+    let decimation = if decimated { 64 } else { 1 };
+    let estimate = SizeEstimate::new(
+        expected_samples(start_dt, end, fs, decimation),
+        bpms_to_acquire(&opts.ring, &opts.bpm_search_terms).len(),
+        format,
+        &opts.layout,
+    );
+    print_log_event(
+        "info",
+        format!("Estimated {}", estimate.describe()).as_str(),
+        estimate.log_fields(),
+    );
+    if estimate.largest() <= opts.size_limit {
+        return;
+    }
+    if opts.yes {
+        print_log_warning(
+            format!(
+                "Going over the size limit of {} as --yes was given",
+                format_size(opts.size_limit)
+            )
+            .as_str(),
+        );
+        return;
+    }
+    print_error_and_exit(
+        format!(
+            "This would need about {}, which is over the size limit of {}. Ask for a shorter \
+             time range or fewer BPMs, raise --size-limit, or give --yes to go ahead anyway",
+            format_size(estimate.largest()),
+            format_size(opts.size_limit)
+        )
+        .as_str(),
+    );
+}
 
+/// The command asking the archiver for the positions of some BPMs, with its trailing newline.
+fn archiver_command(
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
+    bpm_range: &[usize],
+    all_bpms: bool,
+    decimated: bool,
+) -> String {
     let start_seconds = start_dt.timestamp();
-    let start_nanos = start_dt.timestamp_nanos() - start_seconds * 1_000_000_000;
-    let end_seconds = end_dt.timestamp();
-    let end_nanos = end_dt.timestamp_nanos() - end_seconds * 1_000_000_000;
-
-    match ring {
-        Ring::R1 => {
-            port = 12001;
-            bpm_range = (1..37).map(|x| x.to_string()).collect();
-            bpm_cmd_str = "1-36".to_string();
-        }
-        Ring::R3 => {
-            port = 32001;
-            bpm_range = (1..201).map(|x| x.to_string()).collect();
-            bpm_cmd_str = "1-200".to_string();
-        }
-        Ring::Unk => unreachable!("Shouldn't be able to get here..."),
+    let start_nanos = start_dt.timestamp_nanos_opt().unwrap() - start_seconds * 1_000_000_000;
+    // The archiver is asked for data either until a time or for a number of samples.
+    let end_str = match end {
+        RequestEnd::Time(end_dt) => {
+            let end_seconds = end_dt.timestamp();
+            let end_nanos = end_dt.timestamp_nanos_opt().unwrap() - end_seconds * 1_000_000_000;
+            format!("ES{}.{:09}", end_seconds, end_nanos)
+        }
+        RequestEnd::Samples(samples) => format!("N{}", samples),
     };
-    const CHKBYTESIZE: usize = 1;
-    const HDRSIZE: usize = 8;
-    const DATSIZE: usize = 4;
+    let acq_type = if decimated { "DF1" } else { "F" };
+    format!(
+        "R{}M{}S{}.{:09}{}N\n",
+        acq_type,
+        bpm_mask(bpm_range, all_bpms),
+        start_seconds,
+        start_nanos,
+        end_str,
+    )
+}
 
-    let mut datasets = vec![];
+/// The command subscribing to the live data of some BPMs.
+fn subscribe_command(bpm_range: &[usize], all_bpms: bool) -> String {
+    format!("SM{}\n", bpm_mask(bpm_range, all_bpms))
+}
 
-    let acq_type: String = if decimated {
-        "DF1".to_string()
+/// The BPMs of a command, as a range when they are all wanted.
+fn bpm_mask(bpm_range: &[usize], all_bpms: bool) -> String {
+    if all_bpms {
+        format!("1-{}", bpm_range.len())
     } else {
-        "F".to_string()
-    };
+        bpm_range.iter().map(|x| x.to_string()).join(",")
+    }
+}
+
+/// The end of a request.  For a number of samples, this is where the last of them should be.
+fn request_end_time(
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
+    fs: f64,
+    decimation: usize,
+) -> DateTime<FixedOffset> {
+    match end {
+        RequestEnd::Time(end_dt) => end_dt,
+        RequestEnd::Samples(samples) => {
+            let length_ns = samples as f64 * decimation as f64 * 1e9 / fs;
+            in_timezone(*start_dt + Duration::nanoseconds(length_ns.round() as i64))
+        }
+    }
+}
+
+fn get_raw_archived_data(
+    ring: Ring,
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
+    bpm_search_term: &Vec<String>,
+    decimated: bool,
+) -> Result<RawData> {
+    let (host, port) = archiver_address(&ring);
+    const CHKBYTESIZE: usize = 1;
+    const HDRSIZE: usize = 8;
+
     let capacity_divisor = if decimated { 64 } else { 1 };
     print_log_message(format!("capacity_divisor: '{}'", capacity_divisor).as_str());
 
-    let bpms: String = if !bpm_search_term.is_empty() {
-        print_log_message("Searching for BPMs");
-        match get_bpm_number(bpm_search_term, &ring) {
-            Some(ans) => {
-                bpm_range = ans.clone().iter().map(|x| x.to_string()).collect();
-                ans.iter().map(|x| x.to_string()).join(",")
-            }
-            None => {
-                eprintln!("No BPMs found matching {:?}", bpm_search_term);
-                exit(1);
-            }
-        }
-    } else {
-        bpm_cmd_str
-    };
-    print_log_message(format!("Number of BPMs to acquire = {}", bpm_range.len()).as_str());
+    let bpm_range = bpms_to_acquire(&ring, bpm_search_term);
+    print_log_event(
+        "info",
+        format!("Number of BPMs to acquire = {}", bpm_range.len()).as_str(),
+        vec![
+            ("num_bpms", json!(bpm_range.len())),
+            ("bpms", json!(bpm_range)),
+        ],
+    );
 
-    let cmd_str = format!(
-        "R{}M{}S{}.{:09}ES{}.{:09}N\n",
-        acq_type, bpms, start_seconds, start_nanos, end_seconds, end_nanos,
+    let cmd_str = archiver_command(
+        start_dt,
+        end,
+        &bpm_range,
+        bpm_search_term.is_empty(),
+        decimated,
+    );
+    print_log_event(
+        "info",
+        format!("Sending the command: '{}'", cmd_str.trim()).as_str(),
+        vec![("command", json!(cmd_str.trim()))],
     );
-    print_log_message(format!("Sending the command: '{}'", cmd_str.trim()).as_str());
+
+    let fs = match get_fs(ring.clone()) {
+        Ok(result) => result,
+        Err(e) => {
+            print_error_and_exit(format!("{e}").as_str());
+        }
+    };
 
     let mut checkbyte = [0u8; CHKBYTESIZE];
     let mut header = [0u8; HDRSIZE];
-    let total_time = (end_dt.timestamp_nanos() - start_dt.timestamp_nanos()) / 1_000_000_000;
-    let mut buf = Vec::with_capacity((16222400 / capacity_divisor) * total_time as usize);
+    let samples = expected_samples(start_dt, end, fs, capacity_divisor);
+    // The expected size is only a hint, so a request too large to make room for up front is
+    // left to grow as the data arrives.
+    let mut buf = Vec::new();
+    let _ = buf.try_reserve((samples as usize).saturating_mul(bpm_range.len() * 2 * 4));
 
-    let mut stream = std::net::TcpStream::connect((HOST, port))?;
+    let mut stream = std::net::TcpStream::connect((host.as_str(), port))?;
     stream.write_all(cmd_str.as_bytes())?;
 
     print_log_message("Reading data from stream");
     stream.read_exact(&mut checkbyte)?;
     print_log_message(format!("Checkbyte = {:?}", checkbyte).as_str());
     if checkbyte[0] != 0 {
-        eprintln!("Note that checkbyte was non-zero. This indicates that the server detected an error in the response we gave it.")
+        print_log_warning("Note that checkbyte was non-zero. This indicates that the server detected an error in the response we gave it.");
     }
     stream.read_exact(&mut header)?;
     print_log_message(format!("Header = {:?}", header).as_str());
     let mut reader = BufReader::new(&stream);
     let read_bytes = reader.read_to_end(&mut buf)?;
-    print_log_message(format!("Read {} bytes", read_bytes).as_str());
+    print_log_event(
+        "info",
+        format!("Read {} bytes", read_bytes).as_str(),
+        vec![("bytes", json!(read_bytes))],
+    );
 
-    print_log_message("Parsing data");
-    let mut values = Vec::new();
-    for i in (0..buf.len()).step_by(DATSIZE) {
-        let val = i32::from_ne_bytes((&buf[i..i + DATSIZE]).try_into().unwrap());
-        values.push(val);
-    }
-    let num_datapoints = values.len() / (2 * bpm_range.len());
+    Ok(RawData {
+        ring,
+        bpm_range,
+        fs,
+        decimation: Some(capacity_divisor),
+        start_time: *start_dt,
+        request: Some(Request {
+            start: *start_dt,
+            end: request_end_time(start_dt, end, fs, capacity_divisor),
+            command: cmd_str.trim().to_string(),
+            archiver: format!("{}:{}", host, port),
+        }),
+        payload: buf,
+    })
+}
 
-    let fs = match get_fs(ring.clone()) {
-        Ok(result) => result,
-        Err(e) => {
-            eprintln!("{e}");
-            exit(1);
-        }
-    };
-    let timestep_nanoseconds: f64 = 1_000_000_000f64 / fs;
-
-    let ts: Vec<_> = (1..num_datapoints)
-        .map(|x| {
-            (*start_dt
-                + Duration::nanoseconds(
-                    ((x - 1) as f64 * timestep_nanoseconds * capacity_divisor as f64) as i64,
-                ))
-            .format("%Y-%m-%d_%H:%M:%S.%f")
-            .to_string()
-        })
-        .collect();
+fn get_archived_data(
+    ring: Ring,
+    start_dt: &DateTime<FixedOffset>,
+    end: RequestEnd,
+    bpm_search_term: &Vec<String>,
+    decimated: bool,
+) -> Result<Acquisition> {
+    let raw = get_raw_archived_data(ring, start_dt, end, bpm_search_term, decimated)?;
 
-    for (i, _) in bpm_range.iter().enumerate() {
-        let x_vals = values[2 * i..]
-            .iter()
-            .step_by(bpm_range.len() * 2)
-            .cloned()
-            .collect::<Vec<i32>>();
-        let y_vals = values[2 * i + 1..]
-            .iter()
-            .step_by(bpm_range.len() * 2)
-            .cloned()
-            .collect::<Vec<i32>>();
-        let d = BpmData {
-            ring: ring.clone(),
-            bpmnum: bpm_range[i].parse::<usize>().unwrap() - 1,
-            ts: ts.clone(),
-            x: x_vals,
-            y: y_vals,
-        };
-        datasets.push(d);
+    print_log_message("Parsing data");
+    let acq = raw.to_acquisition();
+    if let Some((first, last)) = acq.time_range() {
+        print_log_message(format!("Received data from {} til {}", first, last).as_str());
+    }
+    for gap in &acq.gaps {
+        print_log_warning(format!("There is no data from {} til {}", gap.start, gap.end).as_str());
     }
 
     print_log_message("Returning parsed data");
-    Ok(datasets)
-}
-
-fn print_help(exe_name: &str) {
-    println!("\nUsage:");
-    print!("{exe_name} --ring R1|R3 ");
-    print!("--start YYYY-MM-DDThh:mm:ss.xxx ");
-    print!("--end YYYY-MM-DDThh:mm:ss.xxx ");
-    println!("[-file basename]");
-}
-
-fn print_version(exe_name: &str) {
-    println!("{exe_name} v{VERSION_NUMBER} (2023/09/23)");
+    Ok(acq)
 }
 
 fn main() {
-    let mut args: VecDeque<String> = args().collect();
-    let exe_name = args.pop_front().unwrap();
-    if args.contains(&"--help".to_string()) || args.contains(&"-h".to_string()) {
-        print_help(&exe_name);
-        exit(0);
-    }
-    if args.contains(&"--version".to_string()) {
-        print_version(&exe_name);
-        exit(0);
-    }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => print_error_and_exit(format!("{e}").as_str()),
+    };
+    let command = match config.apply_defaults(Cli::command()) {
+        Ok(command) => command,
+        Err(e) => print_error_and_exit(format!("{e}").as_str()),
+    };
+    set_config(config);
+    let matches = command.get_matches_from(legacy_args(args().collect()));
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    set_address_override(cli.host, cli.port);
+    set_log_format(&cli.log_format);
+    set_timezone(cli.tz);
 
-    let opts: FastArchiverOptions = FastArchiverOptions::build_options(args);
+    match cli.command {
+        Command::Get(args) => get(FastArchiverOptions::from_args(args, false)),
+        Command::FindDump(args) => get(FastArchiverOptions::from_args(args, true)),
+        Command::Live(args) => live(args),
+        Command::Info { files } => info(&files),
+        Command::List(args) => match args.command {
+            Some(ListCommand::Bpms(args)) => list_bpms(&args.ring, &args.bpms),
+            Some(ListCommand::Beamlines { ring }) => list_beamlines(ring.as_ref()),
+            None => match &args.ring {
+                Some(ring) => list_bpms(ring, &args.bpms),
+                None => unreachable!("clap requires --ring without a subcommand"),
+            },
+        },
+        Command::Convert(args) => convert(args),
+        Command::Verify { manifests } => verify(&manifests),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "fars", &mut stdout())
+        }
+    }
+}
 
+fn get(opts: FastArchiverOptions) {
     if !opts.check_options() {
-        eprintln!("Input parameters were not correct");
-        print_help(&exe_name);
-        exit(1);
+        print_error_and_exit("Input parameters were not correct");
+    }
+    if opts.dry_run {
+        dry_run(&opts);
+        return;
     }
 
-    let data;
-    let initial_data;
+    let start_time;
+    let end;
 
     if opts.find_dump {
-        initial_data = match get_archived_data(
+        if opts.check_bpms {
+            check_bpm_names(
+                &opts.ring,
+                &bpms_to_acquire(&opts.ring, &opts.bpm_search_terms),
+            );
+        }
+        check_size(&opts, &opts.start_time, opts.end, true, &OutputFormat::Dat);
+        let initial_data = match get_archived_data(
             opts.ring.clone(),
-            &opts.start_time.unwrap(),
-            &opts.end_time.unwrap(),
+            &opts.start_time,
+            opts.end,
             &opts.bpm_search_terms,
             true,
         ) {
             Ok(reply) => reply,
             _ => {
-                eprintln!("There was a problem getting data from the archiver.");
-                eprintln!("Are you within the MAXIV firewall?");
+                print_log_error("There was a problem getting data from the archiver.");
+                print_log_error("Are you within the MAXIV firewall?");
                 exit(1);
             }
         };
-        print_log_message("Starting file-writing threads.");
-        let pool = ThreadPool::new(7);
-        for bpm in initial_data.clone() {
-            pool.execute(move || {
-                bpm.write_to_file("sparse_data");
-            });
+        // The sparse data is only kept to look at, so it is replaced by every search.
+        if let Err(e) = with_overwrite(Overwrite::Force, || {
+            write_output(&DatWriter, &initial_data, "sparse_data", &Compression::None)
+        }) {
+            print_error_and_exit(format!("Could not write output: {e}").as_str());
         }
-        print_log_message("Waiting for file-write threads to finish.");
-        pool.join();
-        let data_length = initial_data[0].y.len();
+        let reference = initial_data.bpm(0).unwrap();
+        let data_length = reference.y.len();
 
         let dump_index: usize = (0..data_length - 1000)
-            .map(|x| root_mean_square(&initial_data[0].y[x..(x + 1000)]))
+            .map(|x| root_mean_square(&reference.y[x..(x + 1000)]))
             .position(|x| x > 1_000_000.0)
             .unwrap()
             + 1000;
-        // println!("{:#?}", initial_data[0].ts[dump_index].clone());
-        let dump_time: DateTime<Local> = Local
-            .datetime_from_str(
-                &initial_data[0].ts[dump_index].clone(),
-                "%Y-%m-%d_%H:%M:%S%.f",
-            )
-            .unwrap();
-        print_log_message(format!("Found a beam dump at {}", dump_time).as_str());
+        let dump_time: DateTime<FixedOffset> = initial_data.ts.time(dump_index);
+        print_log_event(
+            "info",
+            format!("Found a beam dump at {}", dump_time).as_str(),
+            vec![("dump_time", json!(dump_time))],
+        );
 
-        let start_time = dump_time - Duration::milliseconds(4750);
-        let end_time = dump_time + Duration::milliseconds(250);
+        start_time = in_timezone(dump_time - Duration::milliseconds(4750));
+        let end_time = in_timezone(dump_time + Duration::milliseconds(250));
         print_log_message(format!("Acquiring data from {} til {}", start_time, end_time).as_str());
-        data = match get_archived_data(
-            opts.ring,
-            &start_time,
-            &end_time,
-            &opts.bpm_search_terms,
-            opts.deci,
+        end = RequestEnd::Time(end_time);
+    } else {
+        start_time = opts.start_time;
+        end = opts.end;
+    }
+
+    if opts.check_bpms && !opts.find_dump {
+        check_bpm_names(
+            &opts.ring,
+            &bpms_to_acquire(&opts.ring, &opts.bpm_search_terms),
+        );
+    }
+    check_size(&opts, &start_time, end, opts.deci, &opts.format);
+    let raw = match get_raw_archived_data(
+        opts.ring.clone(),
+        &start_time,
+        end,
+        &opts.bpm_search_terms,
+        opts.deci,
+    ) {
+        Ok(reply) => reply,
+        _ => {
+            print_log_error("There was a problem getting data from the archiver.");
+            print_log_error("This can occur if you are not inside the MAX-IV firewall.");
+            print_log_error(
+                "It can also occur if your regex finds too many BPMs. This is a bug that is being",
+            );
+            print_log_error(
+                "investigated, but for the mean time just acquire all BPMs by not using a regex.",
+            );
+            exit(1);
+        }
+    };
+
+    write_raw_output(&raw, &opts);
+    print_log_message("Done!");
+}
+
+/// Prints what `get` would do, without connecting to the archiver or writing anything.
+fn dry_run(opts: &FastArchiverOptions) {
+    let bpm_range = bpms_to_acquire(&opts.ring, &opts.bpm_search_terms);
+    let all_bpms = opts.bpm_search_terms.is_empty();
+    let decimation = if opts.deci { 64 } else { 1 };
+    let end_time = request_end_time(&opts.start_time, opts.end, NOMINAL_FS, decimation);
+
+    println!("Dry run: nothing will be sent to the archiver or written.");
+    let (host, port) = archiver_address(&opts.ring);
+    println!("Archiver: {}:{}", host, port);
+    println!("BPMs ({}):", bpm_range.len());
+    for bpmnum in &bpm_range {
+        println!(
+            "  {:3} {}",
+            bpmnum,
+            get_bpm_name(bpmnum - 1, &opts.ring).unwrap_or_default()
+        );
+    }
+    for (bpmnum, terms) in overlapping_search_terms(&opts.ring, &opts.bpm_search_terms) {
+        println!(
+            "  Note: {} {} is matched by each of {}, but is only acquired once",
+            bpmnum,
+            get_bpm_name(bpmnum - 1, &opts.ring).unwrap_or_default(),
+            terms.iter().map(|x| format!("'{}'", x)).join(", ")
+        );
+    }
+    match opts.end {
+        RequestEnd::Time(end) => println!(
+            "Time range: {} til {} ({} ms)",
+            opts.start_time,
+            end,
+            (end - opts.start_time).num_milliseconds()
+        ),
+        RequestEnd::Samples(samples) => println!(
+            "Time range: {} samples from {}, until about {} at {} Hz",
+            samples, opts.start_time, end_time, NOMINAL_FS
+        ),
+    }
+    println!(
+        "Stream: {}",
+        if opts.deci {
+            "decimated (1 in 64)"
+        } else {
+            "full"
+        }
+    );
+
+    if opts.find_dump {
+        println!(
+            "Requests: 2, the first to find the beam dump and the second for the data around it"
+        );
+        println!(
+            "  1. {}",
+            archiver_command(&opts.start_time, opts.end, &bpm_range, all_bpms, true).trim()
+        );
+        match output_paths(
+            &DatWriter,
+            &opts.ring,
+            opts.start_time,
+            &bpm_range.iter().map(|x| x - 1).collect::<Vec<_>>(),
+            "sparse_data",
+            &Compression::None,
         ) {
-            Ok(reply) => reply,
-            _ => {
-                eprintln!("There was a problem getting data from the archiver.");
-                eprintln!("This can occur if you are not inside the MAX-IV firewall.");
-                eprintln!("It can also occur if your regex finds too many BPMs. This is a bug that is being");
-                eprintln!("investigated, but for the mean time just acquire all BPMs by not using a regex.");
-                exit(1);
-            }
+            Ok(paths) => println!("     writing {}", paths.join(", ")),
+            Err(e) => print_error_and_exit(format!("Invalid output name: {e}").as_str()),
+        }
+        println!("  2. From 4.75 s before the dump til 250 ms after it, once it has been found");
+    } else {
+        println!("Requests: 1, for the whole time range at once");
+        println!(
+            "  1. {}",
+            archiver_command(&opts.start_time, opts.end, &bpm_range, all_bpms, opts.deci).trim()
+        );
+        let estimate = SizeEstimate::new(
+            expected_samples(&opts.start_time, opts.end, NOMINAL_FS, decimation),
+            bpm_range.len(),
+            &opts.format,
+            &opts.layout,
+        );
+        println!(
+            "Estimated size, assuming {} Hz: {}",
+            NOMINAL_FS,
+            estimate.describe()
+        );
+        if estimate.largest() > opts.size_limit && !opts.yes {
+            println!(
+                "  This is over the size limit of {}, so the request would be refused",
+                format_size(opts.size_limit)
+            );
+        }
+    }
+
+    if opts.file == STDOUT {
+        println!("Output: stdout ({:?} format)", opts.format);
+        return;
+    }
+    let writer = opts.format.writer(&opts.layout);
+    // The files are named after the start of the data, which is only known for a beam dump once
+    // it has been found.
+    let paths = output_paths(
+        writer.as_ref(),
+        &opts.ring,
+        opts.start_time,
+        &bpm_range.iter().map(|x| x - 1).collect::<Vec<_>>(),
+        &opts.file,
+        &opts.compression,
+    );
+    match paths {
+        Ok(paths) if opts.find_dump => {
+            println!(
+                "Output files, for the data around the dump ({}):",
+                paths.len()
+            );
+            print_paths(&paths);
+        }
+        Ok(paths) => {
+            println!("Output files ({}):", paths.len());
+            print_paths(&paths);
+        }
+        Err(e) => print_error_and_exit(format!("Invalid output name: {e}").as_str()),
+    }
+}
+
+fn print_paths(paths: &[String]) {
+    for path in paths {
+        if Path::new(path).exists() {
+            println!("  {} (already exists)", path);
+        } else {
+            println!("  {}", path);
+        }
+    }
+}
+
+fn convert(args: ConvertArgs) {
+    let output = args.output;
+    set_overwrite(output.overwrite());
+    if output.units == Some(Units::Raw)
+        && output.calibration.as_ref().is_some_and(|x| !x.is_empty())
+    {
+        print_error_and_exit(CALIBRATED_RAW_UNITS);
+    }
+    let (format, basename) = match args.to.as_slice() {
+        [format, rest @ ..] => match OutputFormat::from_string(format) {
+            Some(format) => (format, rest.first().cloned().unwrap_or(output.output)),
+            None => print_error_and_exit(format!("Unknown output format '{}'", format).as_str()),
+        },
+        [] => (output.format, output.output),
+    };
+
+    // The output may be given with its extension (e.g., `--to csv out.csv`), which is added back
+    // by the writer.
+    let writer = format.writer(&output.layout);
+    let suffix = format!(".{}{}", writer.extension(), output.compress.extension());
+    let basename = basename
+        .strip_suffix(suffix.as_str())
+        .unwrap_or(basename.as_str())
+        .to_string();
+
+    let mut acq = Acquisition::default();
+    for input in &args.inputs {
+        print_log_message(format!("Reading '{}'", input).as_str());
+        if let Err(e) = read_input(input).and_then(|data| acq.merge(data)) {
+            print_error_and_exit(format!("Could not read '{}': {e}", input).as_str());
+        }
+    }
+    // Positions are written in the units of the input unless told otherwise.
+    if let Some(units) = output.units {
+        acq.units = units;
+    }
+    if acq.calibrated && acq.units == Units::Raw {
+        print_error_and_exit(CALIBRATED_RAW_UNITS);
+    }
+    if let Some(calibration) = output.calibration.filter(|x| !x.is_empty()) {
+        if acq.calibrated {
+            print_error_and_exit("The input has already been calibrated");
+        }
+        if acq.units == Units::Raw {
+            acq.units = Units::Nm;
+        }
+        acq.calibration = calibration;
+    }
+    match write_output(writer.as_ref(), &acq, &basename, &output.compress) {
+        Ok(files) => write_manifest_or_exit(&acq, &files, &basename),
+        Err(e) => print_error_and_exit(format!("Could not write output: {e}").as_str()),
+    }
+    print_log_message("Done!");
+}
+
+/// Streams data from the archiver to stdout as it arrives.
+fn live(args: LiveArgs) {
+    let (host, port) = archiver_address(&args.ring);
+    match stream_live(args) {
+        Ok(()) => print_log_message("Done!"),
+        // Whatever was reading the output has stopped, such as `head`.
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+        Err(e) => print_error_and_exit(
+            format!(
+                "Could not stream data from the archiver at {}:{}: {e}",
+                host, port
+            )
+            .as_str(),
+        ),
+    }
+}
+
+fn stream_live(args: LiveArgs) -> Result<()> {
+    let calibration = args.calibration.clone().unwrap_or_default();
+    if args.units == Some(Units::Raw) && !calibration.is_empty() {
+        print_error_and_exit(CALIBRATED_RAW_UNITS);
+    }
+    // Calibrated positions are no longer integers, so they are written in nm by default.
+    let units = match args.units {
+        Some(units) => units,
+        None if !calibration.is_empty() => Units::Nm,
+        None => Units::Raw,
+    };
+    let fs = sample_rate(&args.ring, false);
+    let bpm_range = bpms_to_acquire(&args.ring, &args.bpms);
+    let (host, port) = archiver_address(&args.ring);
+    let cmd_str = subscribe_command(&bpm_range, args.bpms.is_empty());
+    print_log_event(
+        "info",
+        format!("Sending the command: '{}'", cmd_str.trim()).as_str(),
+        vec![("command", json!(cmd_str.trim()))],
+    );
+
+    let mut stream = std::net::TcpStream::connect((host.as_str(), port))?;
+    stream.write_all(cmd_str.as_bytes())?;
+    let mut reader = BufReader::new(&stream);
+    let mut checkbyte = [0u8; 1];
+    reader.read_exact(&mut checkbyte)?;
+    if checkbyte[0] != 0 {
+        // The archiver explains what was wrong with the command instead.
+        let mut msg = checkbyte.to_vec();
+        reader.read_to_end(&mut msg)?;
+        return Err(Error::other(format!(
+            "the archiver refused '{}': {}",
+            cmd_str.trim(),
+            String::from_utf8_lossy(&msg).trim()
+        )));
+    }
+    // The archiver sends no times with live data, so the samples are timed from the clock of
+    // this machine when the first of them arrives.
+    let start_time = timezone::now();
+
+    let frame_size = bpm_range.len() * 2 * 4;
+    // About a tenth of a second of data is written at a time.
+    let block_samples = ((fs / 10.0) as u64).max(1);
+    let mut out = std::io::BufWriter::new(stdout().lock());
+    let mut received: u64 = 0;
+    loop {
+        let wanted = match args.samples {
+            Some(samples) => block_samples.min(samples - received),
+            None => block_samples,
+        };
+        if wanted == 0 {
+            break;
+        }
+        let wanted_bytes = wanted * frame_size as u64;
+        let mut payload = Vec::new();
+        (&mut reader).take(wanted_bytes).read_to_end(&mut payload)?;
+        let closed = (payload.len() as u64) < wanted_bytes;
+        payload.truncate(payload.len() - payload.len() % frame_size);
+        if payload.is_empty() {
+            break;
+        }
+
+        let offset_ns = (received as f64 * 1e9 / fs).round() as i64;
+        let raw = RawData {
+            ring: args.ring.clone(),
+            bpm_range: bpm_range.clone(),
+            fs,
+            decimation: Some(1),
+            start_time: in_timezone(start_time + Duration::nanoseconds(offset_ns)),
+            request: None,
+            payload,
         };
+        let mut acq = raw.to_acquisition();
+        acq.units = units;
+        acq.calibration = calibration.clone();
+        if received == 0 {
+            write_live_header(
+                &mut out,
+                &acq,
+                &args,
+                &format!("{}:{}", host, port),
+                &cmd_str,
+            )?;
+        }
+        match args.format {
+            OutputFormat::Jsonl => jsonldata::write_records(&mut out, &acq, &args.layout)?,
+            _ => csvdata::write_rows(&mut out, &acq, live_delimiter(&args), &args.layout)?,
+        }
+        out.flush()?;
+        received += acq.num_samples() as u64;
+        if closed {
+            print_log_warning("The archiver closed the connection");
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes what describes a live stream, before its first samples.
+fn write_live_header(
+    out: &mut impl Write,
+    acq: &Acquisition,
+    args: &LiveArgs,
+    archiver: &str,
+    cmd_str: &str,
+) -> Result<()> {
+    // What is known of the samples to come is left out, as only the first of them are in hand.
+    let mut metadata: Vec<_> = acq
+        .metadata()
+        .into_iter()
+        .filter(|(key, _)| !["first_sample", "last_sample", "samples", "gaps"].contains(key))
+        .collect();
+    metadata.push(("archiver", archiver.to_string()));
+    metadata.push(("command", cmd_str.trim().to_string()));
+    metadata.push((
+        "times",
+        "from the clock of this machine when the stream started".to_string(),
+    ));
+    match args.format {
+        OutputFormat::Jsonl => jsonldata::write_metadata(out, &metadata),
+        _ => {
+            csvdata::write_metadata(out, &metadata)?;
+            csvdata::write_header(out, acq, live_delimiter(args), &args.layout)
+        }
+    }
+}
+
+fn live_delimiter(args: &LiveArgs) -> char {
+    if args.format == OutputFormat::Tsv {
+        '\t'
     } else {
-        data = match get_archived_data(
-            opts.ring,
-            &opts.start_time.unwrap(),
-            &opts.end_time.unwrap(),
-            &opts.bpm_search_terms,
-            opts.deci,
-        ) {
-            Ok(reply) => reply,
-            _ => {
-                eprintln!("There was a problem getting data from the archiver.");
-                eprintln!("This can occur if you are not inside the MAX-IV firewall.");
-                eprintln!("It can also occur if your regex finds too many BPMs. This is a bug that is being");
-                eprintln!("investigated, but for the mean time just acquire all BPMs by not using a regex.");
-                exit(1);
+        ','
+    }
+}
+
+/// Prints the description of data files, or of the acquisitions recorded in manifests.
+fn info(files: &[String]) {
+    for fname in files {
+        let (metadata, bpms): (Vec<(String, String)>, Vec<String>) = if fname
+            .ends_with("manifest.json")
+        {
+            match Manifest::read(fname) {
+                Ok(manifest) => (
+                    manifest.acquisition.into_iter().collect(),
+                    manifest
+                        .files
+                        .into_iter()
+                        .flat_map(|file| file.bpms)
+                        .unique()
+                        .collect(),
+                ),
+                Err(e) => print_error_and_exit(format!("Could not read '{}': {e}", fname).as_str()),
+            }
+        } else {
+            match read_input(fname) {
+                // Files without a header of their own are described from their contents, which
+                // say nothing of when, where or by which version they were written.
+                Ok(acq) if acq.header.is_empty() => (
+                    acq.metadata()
+                        .into_iter()
+                        .filter(|(key, _)| !["fars_version", "written", "host"].contains(key))
+                        .map(|(key, value)| (key.to_string(), value))
+                        .collect(),
+                    acq.bpm_names(),
+                ),
+                Ok(acq) => (acq.header.clone(), acq.bpm_names()),
+                Err(e) => print_error_and_exit(format!("Could not read '{}': {e}", fname).as_str()),
             }
         };
+        println!("{}:", fname);
+        for (key, value) in metadata {
+            println!("  {}: {}", key, value);
+        }
+        println!("  bpms: {}", bpms.join(", "));
     }
+}
 
-    print_log_message("Starting file-writing threads.");
-    let pool = ThreadPool::new(7);
-    for bpm in data {
-        let basename = opts.file.clone();
-        pool.execute(move || {
-            bpm.write_to_file(&basename);
-        });
+/// Prints the number, name and cell of the BPMs of a ring, or of those matching the search
+/// terms, along with the beamlines they flank.
+fn list_bpms(ring: &Ring, search_terms: &Vec<String>) {
+    let bpmnums: Vec<usize> = if search_terms.is_empty() {
+        (1..=ring.bpm_names().len()).collect()
+    } else {
+        get_bpm_number(search_terms, ring)
+            .unwrap_or_default()
+            .into_iter()
+            .sorted()
+            .dedup()
+            .collect()
+    };
+    if bpmnums.is_empty() {
+        print_error_and_exit(format!("No BPMs found matching {:?}", search_terms).as_str());
+    }
+    let beamlines = ring.beamlines();
+    let rows: Vec<(usize, String, String, String)> = bpmnums
+        .into_iter()
+        .filter_map(|bpmnum| {
+            let name = get_bpm_name(bpmnum - 1, ring)?;
+            let flanked = beamlines
+                .iter()
+                .filter(|(_, bpms)| bpms.contains(&name))
+                .map(|(beamline, _)| *beamline)
+                .join(", ");
+            let cell = get_bpm_cell(&name).unwrap_or_default();
+            Some((bpmnum, name, cell, flanked))
+        })
+        .collect();
+    let width = rows.iter().map(|x| x.1.len()).max().unwrap_or_default();
+    println!("{:>4}  {:width$}  {:4}  beamlines", "id", "name", "cell");
+    for (bpmnum, name, cell, flanked) in rows {
+        let row = format!("{:>4}  {:width$}  {:4}  {}", bpmnum, name, cell, flanked);
+        println!("{}", row.trim_end());
+    }
+}
+
+/// Prints the beamlines that can be used to select BPMs, in one ring or in all of them, with the
+/// numbers and names of the BPMs flanking them.
+fn list_beamlines(ring: Option<&Ring>) {
+    let rings = match ring {
+        Some(ring) => vec![ring.clone()],
+        None => ring_names()
+            .into_iter()
+            .filter_map(Ring::from_name)
+            .collect(),
+    };
+    let rows: Vec<(String, &str, String)> = rings
+        .iter()
+        .flat_map(|ring| {
+            ring.beamlines().into_iter().map(move |(beamline, bpms)| {
+                let bpms = bpms
+                    .iter()
+                    .map(
+                        |name| match ring.bpm_names().iter().position(|x| x == name) {
+                            Some(bpmnum) => format!("{} ({})", name, bpmnum + 1),
+                            None => name.to_string(),
+                        },
+                    )
+                    .join(", ");
+                (ring.to_string(), beamline, bpms)
+            })
+        })
+        .collect();
+    if rows.is_empty() {
+        print_error_and_exit("No beamlines are defined");
+    }
+    let ring_width = rows.iter().map(|x| x.0.len()).chain([4]).max().unwrap();
+    let width = rows.iter().map(|x| x.1.len()).chain([8]).max().unwrap();
+    println!("{:ring_width$}  {:width$}  bpms", "ring", "beamline");
+    for (ring, beamline, bpms) in rows {
+        println!("{:ring_width$}  {:width$}  {}", ring, beamline, bpms);
+    }
+}
+
+fn write_raw_output(raw: &RawData, opts: &FastArchiverOptions) {
+    let writer = opts.format.writer(&opts.layout);
+    let result = if opts.units == Units::Raw {
+        writer
+            .write_raw(raw, &opts.file, &opts.compression)
+            .map(|files| (raw.header(), files))
+    } else {
+        print_log_message("Parsing data");
+        let acq = Acquisition {
+            units: opts.units,
+            calibration: opts.calibration.clone(),
+            ..raw.to_acquisition()
+        };
+        write_output(writer.as_ref(), &acq, &opts.file, &opts.compression).map(|files| (acq, files))
+    };
+    match result {
+        Ok((acq, files)) => write_manifest_or_exit(&acq, &files, &opts.file),
+        Err(e) => print_error_and_exit(format!("Could not write output: {e}").as_str()),
+    }
+}
+
+fn write_manifest_or_exit(acq: &Acquisition, files: &[WrittenFile], basename: &str) {
+    if files.is_empty() {
+        return;
+    }
+    match write_manifest(acq, files, basename) {
+        Ok(fname) => print_log_message(format!("Wrote the manifest to '{}'", fname).as_str()),
+        Err(e) => print_error_and_exit(format!("Could not write the manifest: {e}").as_str()),
+    }
+}
+
+/// Checks the files listed in manifests, exiting with an error if any are missing or changed.
+fn verify(manifests: &[String]) {
+    let mut failures = 0;
+    for fname in manifests {
+        let manifest = match Manifest::read(fname) {
+            Ok(manifest) => manifest,
+            Err(e) => print_error_and_exit(format!("Could not read '{}': {e}", fname).as_str()),
+        };
+        let dir = Path::new(fname).parent().unwrap_or(Path::new(""));
+        for (file, result) in manifest.verify(dir) {
+            let path = dir.join(&file.path);
+            match result {
+                Ok(()) => println!("{}: OK", path.display()),
+                Err(e) => {
+                    println!("{}: FAILED ({e})", path.display());
+                    failures += 1;
+                }
+            }
+        }
+    }
+    if failures > 0 {
+        print_error_and_exit(format!("{} files failed verification", failures).as_str());
     }
-    print_log_message("Waiting for file-write threads to finish.");
-    pool.join();
-    print_log_message("Done!");
 }
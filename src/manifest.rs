@@ -0,0 +1,147 @@
+use crate::acquisition::{Acquisition, Request};
+use crate::compress::{self, would_clobber, Compression};
+use crate::template::is_template;
+use crate::writer::WrittenFile;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{copy, Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// A record of the files written for an acquisition, so that they can be checked later.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The description of the acquisition written in the headers of text files.
+    pub acquisition: BTreeMap<String, String>,
+    pub request: Option<Request>,
+    pub files: Vec<ManifestFile>,
+}
+
+/// One file listed in a manifest.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// The path of the file, relative to the manifest.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub samples: usize,
+    pub bpms: Vec<String>,
+}
+
+impl Manifest {
+    /// Describes files that have just been written, reading them back to find their checksums.
+    pub fn new(acq: &Acquisition, files: &[WrittenFile], dir: &Path) -> Result<Self> {
+        let files = files
+            .iter()
+            .map(|file| {
+                let (size, sha256) = checksum(Path::new(&file.path))?;
+                Ok(ManifestFile {
+                    path: relative_to(Path::new(&file.path), dir),
+                    size,
+                    sha256,
+                    samples: file.samples,
+                    bpms: file.bpms.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            acquisition: acq
+                .metadata()
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            request: acq.request.clone(),
+            files,
+        })
+    }
+
+    pub fn read(fname: &str) -> Result<Self> {
+        Ok(serde_json::from_reader(compress::open(fname)?)?)
+    }
+
+    /// Checks the size and checksum of every file, given the directory holding the manifest.
+    pub fn verify(&self, dir: &Path) -> Vec<(&ManifestFile, Result<()>)> {
+        self.files
+            .iter()
+            .map(|file| {
+                let result = checksum(&dir.join(&file.path)).and_then(|(size, sha256)| {
+                    if size != file.size {
+                        Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{} bytes long, but {} were written", size, file.size),
+                        ))
+                    } else if sha256 != file.sha256 {
+                        Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "the checksum does not match",
+                        ))
+                    } else {
+                        Ok(())
+                    }
+                });
+                (file, result)
+            })
+            .collect()
+    }
+}
+
+/// Writes the manifest for the files written from an acquisition, returning its name.
+///
+/// The manifest is written to `{basename}.manifest.json`, or, if the basename is a template, to
+/// `{ring}_{start}.manifest.json` in the deepest directory holding all of the files, since the
+/// files of several acquisitions may share that directory.  A number is added to the name if an
+/// earlier acquisition already wrote a manifest there, unless `--force` was given.
+pub fn write_manifest(acq: &Acquisition, files: &[WrittenFile], basename: &str) -> Result<String> {
+    let fname = if is_template(basename) {
+        let dir = common_dir(files);
+        let stem = format!(
+            "{}_{}",
+            acq.ring,
+            acq.start_time().format("%Y-%m-%dT%H%M%S%.3f")
+        );
+        let mut fname = dir.join(format!("{}.manifest.json", stem));
+        let mut event = 0;
+        while would_clobber(&fname.to_string_lossy()) {
+            event += 1;
+            fname = dir.join(format!("{}_{}.manifest.json", stem, event));
+        }
+        fname
+    } else {
+        PathBuf::from(format!("{}.manifest.json", basename))
+    };
+    let dir = fname.parent().unwrap_or(Path::new(""));
+    let manifest = Manifest::new(acq, files, dir)?;
+    let fname = fname.to_string_lossy().to_string();
+    let mut file = Compression::None.create(&fname)?;
+    serde_json::to_writer_pretty(&mut file, &manifest)?;
+    file.finish()?;
+    Ok(fname)
+}
+
+/// The size and SHA-256 of a file.
+fn checksum(path: &Path) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+fn common_dir(files: &[WrittenFile]) -> PathBuf {
+    let mut dirs = files
+        .iter()
+        .map(|file| Path::new(&file.path).parent().unwrap_or(Path::new("")));
+    let mut common = dirs.next().unwrap_or(Path::new("")).to_path_buf();
+    for dir in dirs {
+        while !dir.starts_with(&common) {
+            common = common.parent().unwrap_or(Path::new("")).to_path_buf();
+        }
+    }
+    common
+}
+
+fn relative_to(path: &Path, dir: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
@@ -0,0 +1,419 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{get_bpm_from_name, BpmData, TimeAxis};
+use crate::compress::{self, Compression};
+use crate::units::Units;
+use crate::writer::OutputWriter;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// The arrays that can be stored in an `.npy` file by this module.
+#[derive(Debug, PartialEq)]
+enum NpyArray {
+    I4(Vec<i32>),
+    I8(Vec<i64>),
+    F8(Vec<f64>),
+    Str(Vec<String>),
+}
+
+pub struct NpzWriter;
+
+impl OutputWriter for NpzWriter {
+    fn extension(&self) -> &'static str {
+        "npz"
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        write_npz(acq, fname, compression)
+    }
+}
+
+/// Writes an acquisition as a numpy `.npz` archive.
+///
+/// The archive contains the arrays `t` (nanoseconds since the Unix epoch), `bpm_names`, `bpmnum`, and `x`
+/// and `y` with the shape `(number of BPMs, number of samples)`.  When the positions are converted
+/// to physical units, `x` and `y` are floats and the archive also contains the array `units`, and
+/// when a calibration has been applied to them, the array `calibration`.
+pub fn write_npz(acq: &Acquisition, fname: &str, compression: &Compression) -> Result<()> {
+    let data = &acq.bpms;
+    // The arrays must be rectangular, so only the samples that every BPM has are written, as
+    // `write_parquet` writes only the samples that each BPM has.
+    let n = data
+        .iter()
+        .map(|bpm| bpm.x.len().min(bpm.y.len()))
+        .fold(acq.num_samples(), usize::min);
+    let ts = (0..n).map(|i| acq.ts.time_ns(i)).collect();
+    let names = acq.bpm_names();
+    let bpmnums = data.iter().map(|bpm| bpm.bpmnum as i64).collect();
+    let (x, y) = match acq.units {
+        Units::Raw => (
+            NpyArray::I4(data.iter().flat_map(|bpm| bpm.x[..n].to_vec()).collect()),
+            NpyArray::I4(data.iter().flat_map(|bpm| bpm.y[..n].to_vec()).collect()),
+        ),
+        _ => {
+            let (mut x, mut y) = (vec![], vec![]);
+            for bpm in data {
+                let (bpm_x, bpm_y) = acq.positions(bpm);
+                x.extend((0..n).map(|i| bpm_x.get(i).as_f64()));
+                y.extend((0..n).map(|i| bpm_y.get(i).as_f64()));
+            }
+            (NpyArray::F8(x), NpyArray::F8(y))
+        }
+    };
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut arrays = vec![
+        ("t", vec![n], NpyArray::I8(ts)),
+        ("bpm_names", vec![data.len()], NpyArray::Str(names)),
+        ("bpmnum", vec![data.len()], NpyArray::I8(bpmnums)),
+        ("x", vec![data.len(), n], x),
+        ("y", vec![data.len(), n], y),
+    ];
+    if let Some(units) = acq.units.name() {
+        arrays.push(("units", vec![1], NpyArray::Str(vec![units.to_string()])));
+    }
+    if acq.calibrated || !acq.calibration.is_empty() {
+        let note = "per-BPM gain and offset applied".to_string();
+        arrays.push(("calibration", vec![1], NpyArray::Str(vec![note])));
+    }
+    for (name, shape, array) in arrays {
+        zip.start_file(format!("{}.npy", name), options)?;
+        write_npy(&mut zip, &shape, &array)?;
+    }
+    let buf = zip.finish()?.into_inner();
+
+    let mut file = compression.create(fname)?;
+    file.write_all(&buf)?;
+    file.finish()
+}
+
+/// Reads an `.npz` archive written by `write_npz`.
+pub fn read_npz(fname: &str) -> Result<Acquisition> {
+    let mut zip = ZipArchive::new(Cursor::new(compress::read_to_end(fname)?))?;
+    let mut read_array = |name: &str| -> Result<(Vec<usize>, NpyArray)> {
+        let mut buf = Vec::new();
+        zip.by_name(format!("{}.npy", name).as_str())?
+            .read_to_end(&mut buf)?;
+        read_npy(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", name, e)))
+    };
+
+    let units = match read_array("units") {
+        Ok((_, NpyArray::Str(units))) => units.first().and_then(|x| Units::from_string(x)),
+        Ok(_) => None,
+        Err(_) => Some(Units::Raw),
+    };
+    let calibrated = matches!(read_array("calibration"), Ok((_, NpyArray::Str(_))));
+    let (ts, names, x, y) = match (
+        read_array("t")?,
+        read_array("bpm_names")?,
+        read_array("x")?,
+        read_array("y")?,
+        units,
+    ) {
+        (
+            (_, NpyArray::I8(ts)),
+            (_, NpyArray::Str(names)),
+            (shape, NpyArray::I4(x)),
+            (_, NpyArray::I4(y)),
+            Some(Units::Raw),
+        ) if shape.len() == 2 && x.len() == y.len() => {
+            let to_f64 = |vals: Vec<i32>| vals.into_iter().map(f64::from).collect::<Vec<_>>();
+            (ts, names, to_f64(x), to_f64(y))
+        }
+        (
+            (_, NpyArray::I8(ts)),
+            (_, NpyArray::Str(names)),
+            (shape, NpyArray::F8(x)),
+            (_, NpyArray::F8(y)),
+            Some(_),
+        ) if shape.len() == 2 && x.len() == y.len() => (ts, names, x, y),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not contain the expected arrays", fname),
+            ))
+        }
+    };
+
+    let n = ts.len();
+    if x.len() != names.len() * n {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} does not hold {} samples of each of {} BPMs",
+                fname,
+                n,
+                names.len()
+            ),
+        ));
+    }
+    let units = units.unwrap_or_default();
+    let mut acq = Acquisition::default();
+    for (i, name) in names.iter().enumerate() {
+        let (ring, bpmnum) = get_bpm_from_name(name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unknown BPM '{}'", name)))?;
+        let bpm = acq.get_or_insert_bpm(ring, bpmnum)?;
+        *bpm = BpmData {
+            bpmnum,
+            ..Default::default()
+        };
+        for j in i * n..(i + 1) * n {
+            bpm.push(units, x[j], y[j]);
+        }
+    }
+    acq.set_time_axis(TimeAxis::from_nanos(&ts));
+    acq.units = units;
+    acq.calibrated = calibrated;
+    Ok(acq)
+}
+
+fn write_npy(file: &mut impl Write, shape: &[usize], array: &NpyArray) -> Result<()> {
+    let descr = match array {
+        NpyArray::I4(_) => "<i4".to_string(),
+        NpyArray::I8(_) => "<i8".to_string(),
+        NpyArray::F8(_) => "<f8".to_string(),
+        NpyArray::Str(vals) => format!(
+            "<U{}",
+            vals.iter()
+                .map(|x| x.chars().count())
+                .max()
+                .unwrap_or(1)
+                .max(1)
+        ),
+    };
+    let shape_str = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape_str
+    );
+    // The magic, version and header length take 10 bytes, and the whole header must be padded
+    // with spaces to a multiple of 64 bytes, ending in a newline.
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    file.write_all(NPY_MAGIC)?;
+    file.write_all(&[1, 0])?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    match array {
+        NpyArray::I4(vals) => {
+            for val in vals {
+                file.write_all(&val.to_le_bytes())?;
+            }
+        }
+        NpyArray::I8(vals) => {
+            for val in vals {
+                file.write_all(&val.to_le_bytes())?;
+            }
+        }
+        NpyArray::F8(vals) => {
+            for val in vals {
+                file.write_all(&val.to_le_bytes())?;
+            }
+        }
+        NpyArray::Str(vals) => {
+            let width: usize = descr[2..].parse().unwrap();
+            for val in vals {
+                let mut chars: Vec<u32> = val.chars().map(|c| c as u32).collect();
+                chars.resize(width, 0);
+                for c in chars {
+                    file.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_npy(buf: &[u8]) -> std::result::Result<(Vec<usize>, NpyArray), String> {
+    if !buf.starts_with(NPY_MAGIC) || buf.len() < 10 {
+        return Err("Not an npy file".to_string());
+    }
+    let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+    let header = buf
+        .get(10..10 + header_len)
+        .ok_or("The header is truncated")?;
+    let header = std::str::from_utf8(header).map_err(|e| e.to_string())?;
+    let body = &buf[10 + header_len..];
+
+    let field = |key: &str| -> Option<&str> {
+        let start = header.find(key)? + key.len();
+        Some(header[start..].trim_start_matches([' ', ':']))
+    };
+    let descr = field("'descr'")
+        .and_then(|x| x.strip_prefix('\''))
+        .and_then(|x| x.split_once('\''))
+        .map(|x| x.0)
+        .ok_or("No descr in header")?;
+    if field("'fortran_order'").map(|x| x.starts_with("True")) != Some(false) {
+        return Err("Fortran ordered arrays are not supported".to_string());
+    }
+    let shape: Vec<usize> = field("'shape'")
+        .and_then(|x| x.strip_prefix('('))
+        .and_then(|x| x.split_once(')'))
+        .map(|x| x.0)
+        .ok_or("No shape in header")?
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.trim().parse::<usize>().map_err(|e| e.to_string()))
+        .collect::<std::result::Result<_, _>>()?;
+
+    let array = match descr {
+        "<i4" => NpyArray::I4(
+            body.chunks_exact(4)
+                .map(|x| i32::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        "<i8" => NpyArray::I8(
+            body.chunks_exact(8)
+                .map(|x| i64::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        "<f8" => NpyArray::F8(
+            body.chunks_exact(8)
+                .map(|x| f64::from_le_bytes(x.try_into().unwrap()))
+                .collect(),
+        ),
+        descr if descr.starts_with("<U") => {
+            let width: usize = descr[2..].parse().map_err(|_| "Bad string width")?;
+            NpyArray::Str(
+                body.chunks_exact(4 * width)
+                    .map(|x| {
+                        x.chunks_exact(4)
+                            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+                            .take_while(|c| *c != 0)
+                            .filter_map(char::from_u32)
+                            .collect()
+                    })
+                    .collect(),
+            )
+        }
+        _ => return Err(format!("Unsupported dtype '{}'", descr)),
+    };
+    let len = match &array {
+        NpyArray::I4(vals) => vals.len(),
+        NpyArray::I8(vals) => vals.len(),
+        NpyArray::F8(vals) => vals.len(),
+        NpyArray::Str(vals) => vals.len(),
+    };
+    if len != shape.iter().product::<usize>() {
+        return Err(format!(
+            "Expected {} values for the shape {:?}, but found {}",
+            shape.iter().product::<usize>(),
+            shape,
+            len
+        ));
+    }
+    Ok((shape, array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+    use crate::bpmdata::Ring;
+
+    fn round_trip(shape: &[usize], array: NpyArray) {
+        let mut buf = vec![];
+        write_npy(&mut buf, shape, &array).unwrap();
+        // The header is padded so that the data starts on a 64-byte boundary.
+        let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(read_npy(&buf), Ok((shape.to_vec(), array)));
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        round_trip(&[3], NpyArray::I4(vec![0, i32::MIN, i32::MAX]));
+        round_trip(
+            &[2, 2],
+            NpyArray::I8(vec![1, -2, i64::MAX, 1_696_413_600_000_000_000]),
+        );
+        round_trip(&[2, 1], NpyArray::F8(vec![-0.25, 1e-9]));
+        round_trip(&[0], NpyArray::I4(vec![]));
+        round_trip(
+            &[3],
+            NpyArray::Str(vec![
+                "R3-301M2/DIA/BPM-02".to_string(),
+                "µm".to_string(),
+                String::new(),
+            ]),
+        );
+    }
+
+    #[test]
+    fn npy_refuses_bad_input() {
+        let mut buf = vec![];
+        write_npy(&mut buf, &[4], &NpyArray::I4(vec![1, 2, 3, 4])).unwrap();
+        assert!(read_npy(&buf[..buf.len() - 4]).is_err());
+        assert!(read_npy(&buf[..20]).is_err());
+        assert!(read_npy(b"not an npy file").is_err());
+    }
+
+    fn acquisition(lengths: &[usize]) -> Acquisition {
+        let ring = Ring::from_name("R3").unwrap();
+        let ts: Vec<i64> = (0..4)
+            .map(|i| 1_696_413_600_000_000_000 + i * 100_000)
+            .collect();
+        let bpms = lengths
+            .iter()
+            .enumerate()
+            .map(|(bpmnum, &len)| BpmData {
+                bpmnum,
+                x: (0..len as i32).map(|i| i * 10 + bpmnum as i32).collect(),
+                y: (0..len as i32).map(|i| -i).collect(),
+                scaled: None,
+            })
+            .collect();
+        Acquisition::new(ring, TimeAxis::from_nanos(&ts), bpms)
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        let acq = acquisition(&[4, 4, 4]);
+        let read = write_and_read(&acq, "round-trip.npz", write_npz, read_npz);
+        assert_eq!(read.ring, acq.ring);
+        assert_eq!(read.ts, acq.ts);
+        assert_eq!(read.units, Units::Raw);
+        for (read, written) in read.bpms.iter().zip(&acq.bpms) {
+            assert_eq!(read.bpmnum, written.bpmnum);
+            assert_eq!(read.x, written.x);
+            assert_eq!(read.y, written.y);
+        }
+    }
+
+    #[test]
+    fn npz_round_trip_with_units_and_calibration() {
+        for (name, acq) in acquisitions() {
+            let read = write_and_read(&acq, &format!("{}.npz", name), write_npz, read_npz);
+            assert_same_data(&read, &acq);
+        }
+    }
+
+    #[test]
+    fn npz_only_writes_the_samples_every_bpm_has() {
+        let read = write_and_read(&acquisition(&[4, 2, 3]), "uneven.npz", write_npz, read_npz);
+        assert_eq!(read.num_samples(), 2);
+        assert!(read.bpms.iter().all(|bpm| bpm.x.len() == 2));
+    }
+}
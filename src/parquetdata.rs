@@ -0,0 +1,188 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{get_bpm_from_name, TimeAxis};
+use crate::compress::{self, Compression};
+use crate::units::{Positions, Units};
+use crate::writer::OutputWriter;
+use bytes::Bytes;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::{Row, RowAccessor};
+use parquet::schema::parser::parse_message_type;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+/// The schema of the table, with a placeholder for the type of the positions.
+const SCHEMA: &str = "
+    message fa_data {
+        REQUIRED INT64 t (TIMESTAMP(NANOS, true));
+        REQUIRED BYTE_ARRAY bpm (UTF8);
+        REQUIRED INT32 bpmnum;
+        REQUIRED {position} x;
+        REQUIRED {position} y;
+    }
+";
+
+/// The key of the file metadata recording the units of the positions.
+const UNITS_KEY: &str = "units";
+
+/// The key of the file metadata recording that a calibration was applied to the positions.
+const CALIBRATION_KEY: &str = "calibration";
+
+pub struct ParquetWriter;
+
+impl OutputWriter for ParquetWriter {
+    fn extension(&self) -> &'static str {
+        "parquet"
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        write_parquet(acq, fname, compression)
+    }
+}
+
+/// Writes an acquisition as a Parquet table with one row per sample per BPM.
+///
+/// Each BPM is written as its own row group, so readers can pick out single BPMs cheaply.  When the
+/// positions are converted to physical units they are written as doubles, and the units are
+/// recorded in the file metadata, as is any calibration applied to them.
+pub fn write_parquet(acq: &Acquisition, fname: &str, compression: &Compression) -> Result<()> {
+    let position_type = match acq.units {
+        Units::Raw => "INT32",
+        _ => "DOUBLE",
+    };
+    let schema = parse_message_type(SCHEMA.replace("{position}", position_type).as_str())
+        .map_err(Error::other)?;
+    let mut metadata = vec![];
+    if let Some(units) = acq.units.name() {
+        metadata.push(KeyValue::new(UNITS_KEY.to_string(), units.to_string()));
+    }
+    if acq.calibrated || !acq.calibration.is_empty() {
+        let note = "per-BPM gain and offset applied".to_string();
+        metadata.push(KeyValue::new(CALIBRATION_KEY.to_string(), note));
+    }
+    let props = Arc::new(
+        WriterProperties::builder()
+            .set_key_value_metadata(Some(metadata).filter(|x| !x.is_empty()))
+            .build(),
+    );
+    let schema = Arc::new(schema);
+    let mut writer = SerializedFileWriter::new(compression.create(fname)?, schema, props)
+        .map_err(Error::other)?;
+
+    for bpm in &acq.bpms {
+        let n = acq.num_samples().min(bpm.x.len()).min(bpm.y.len());
+        let name = ByteArray::from(acq.bpm_name(bpm).as_str());
+        let ts: Vec<i64> = (0..n).map(|i| acq.ts.time_ns(i)).collect();
+        let names = vec![name; n];
+        let bpmnums = vec![bpm.bpmnum as i32; n];
+        let (x, y) = acq.positions(bpm);
+        let scaled =
+            |pos: &Positions| -> Vec<f64> { (0..n).map(|i| pos.get(i).as_f64()).collect() };
+
+        let mut row_group = writer.next_row_group().map_err(Error::other)?;
+        let mut column = 0;
+        while let Some(mut col_writer) = row_group.next_column().map_err(Error::other)? {
+            match column {
+                0 => col_writer.typed::<Int64Type>().write_batch(&ts, None, None),
+                1 => col_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&names, None, None),
+                2 => col_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&bpmnums, None, None),
+                3 | 4 if acq.units != Units::Raw => {
+                    let pos = if column == 3 { &x } else { &y };
+                    col_writer
+                        .typed::<DoubleType>()
+                        .write_batch(&scaled(pos), None, None)
+                }
+                3 => col_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&bpm.x[..n], None, None),
+                _ => col_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&bpm.y[..n], None, None),
+            }
+            .map_err(Error::other)?;
+            col_writer.close().map_err(Error::other)?;
+            column += 1;
+        }
+        row_group.close().map_err(Error::other)?;
+    }
+    writer.into_inner().map_err(Error::other)?.finish()
+}
+
+/// Reads a Parquet table written by `write_parquet`.
+pub fn read_parquet(fname: &str) -> Result<Acquisition> {
+    let reader = SerializedFileReader::new(Bytes::from(compress::read_to_end(fname)?))
+        .map_err(Error::other)?;
+    let metadata = |key: &str| {
+        reader
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .and_then(|metadata| metadata.iter().find(|x| x.key == key))
+            .and_then(|x| x.value.clone())
+    };
+    let calibrated = metadata(CALIBRATION_KEY).is_some();
+    let units = match metadata(UNITS_KEY).as_deref() {
+        Some(units) => Units::from_string(units).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, format!("Unknown units '{}'", units))
+        })?,
+        None => Units::Raw,
+    };
+    let position = |row: &Row, i: usize| -> Result<f64> {
+        match units {
+            Units::Raw => row.get_int(i).map(f64::from),
+            _ => row.get_double(i),
+        }
+        .map_err(Error::other)
+    };
+    let mut acq = Acquisition::default();
+    let mut ts: Vec<i64> = vec![];
+
+    for row in reader.get_row_iter(None).map_err(Error::other)? {
+        let row = row.map_err(Error::other)?;
+        let name = row.get_string(1).map_err(Error::other)?;
+        let (ring, bpmnum) = get_bpm_from_name(name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unknown BPM '{}'", name)))?;
+        let bpm = acq.get_or_insert_bpm(ring, bpmnum)?;
+        let (x, y) = (position(&row, 3)?, position(&row, 4)?);
+        bpm.push(units, x, y);
+        if bpm.x.len() > ts.len() {
+            ts.push(row.get_long(0).map_err(Error::other)?);
+        }
+    }
+
+    acq.set_time_axis(TimeAxis::from_nanos(&ts));
+    acq.units = units;
+    acq.calibrated = calibrated;
+    Ok(acq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acquisition::testing::*;
+
+    #[test]
+    fn parquet_round_trip() {
+        for (name, acq) in acquisitions() {
+            let read = write_and_read(
+                &acq,
+                &format!("{}.parquet", name),
+                write_parquet,
+                read_parquet,
+            );
+            assert_same_data(&read, &acq);
+        }
+    }
+}
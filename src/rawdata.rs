@@ -0,0 +1,330 @@
+use crate::acquisition::{Acquisition, Gap, Request};
+use crate::bpmdata::{get_bpm_name, BpmData, Ring, TimeAxis};
+use crate::compress::{self, check_overwrite, output_name, Compression, STDOUT};
+use crate::logging::{json_logs, print_log_event};
+use crate::template::{first_free_event, is_template, TemplateFields};
+use crate::timespec::check_time_range;
+use crate::units::Units;
+use crate::writer::{output_file_name, OutputWriter, WrittenFile};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{stderr, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+const DATSIZE: usize = 4;
+
+/// The payload returned by the archiver, exactly as it came off the wire.
+///
+/// The payload is interleaved little-endian i32 values, ordered as x then y for each BPM in
+/// `bpm_range`, repeated for every sample.
+#[derive(Debug, Clone)]
+pub struct RawData {
+    pub ring: Ring,
+    pub bpm_range: Vec<usize>,
+    pub fs: f64,
+    /// As in `Acquisition::decimation`: if it is not known, `fs` is the rate of the samples.
+    pub decimation: Option<usize>,
+    pub start_time: DateTime<FixedOffset>,
+    pub request: Option<Request>,
+    pub payload: Vec<u8>,
+}
+
+/// The JSON file written alongside a raw binary dump, describing how to interpret it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawSidecar {
+    pub data_file: String,
+    pub ring: String,
+    pub bpms: Vec<usize>,
+    pub bpm_names: Vec<String>,
+    pub fs: f64,
+    /// Left out for data converted from files that did not record it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decimation: Option<usize>,
+    pub start_time: DateTime<FixedOffset>,
+    pub num_samples: usize,
+    pub layout: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<Request>,
+}
+
+impl RawData {
+    pub fn num_samples(&self) -> usize {
+        self.payload.len() / (2 * DATSIZE * self.bpm_range.len())
+    }
+
+    pub fn values(&self) -> Vec<i32> {
+        self.payload
+            .chunks_exact(DATSIZE)
+            .map(|x| i32::from_le_bytes(x.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Everything known about the acquisition apart from the positions themselves, without
+    /// parsing the payload.
+    pub fn header(&self) -> Acquisition {
+        let num_datapoints = self.num_samples();
+        let timestep_nanoseconds: f64 = 1_000_000_000f64 / self.fs;
+        let ts = TimeAxis::new(
+            &self.start_time,
+            timestep_nanoseconds * self.decimation.unwrap_or(1) as f64,
+            num_datapoints,
+        );
+
+        // The archiver stops early, rather than failing, when asked for data it does not have.
+        let mut gaps = vec![];
+        if let Some(request) = &self.request {
+            let end = ts.time(num_datapoints);
+            if (request.end - end).num_nanoseconds().unwrap_or(i64::MAX) as f64 > ts.period_ns {
+                gaps.push(Gap {
+                    start: end,
+                    end: request.end,
+                });
+            }
+        }
+
+        Acquisition {
+            ring: self.ring.clone(),
+            fs: self.fs,
+            decimation: self.decimation,
+            request: self.request.clone(),
+            gaps,
+            ts,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_acquisition(&self) -> Acquisition {
+        let values = self.values();
+
+        let mut datasets = vec![];
+        for (i, bpmnum) in self.bpm_range.iter().enumerate() {
+            let x_vals = values[2 * i..]
+                .iter()
+                .step_by(self.bpm_range.len() * 2)
+                .cloned()
+                .collect::<Vec<i32>>();
+            let y_vals = values[2 * i + 1..]
+                .iter()
+                .step_by(self.bpm_range.len() * 2)
+                .cloned()
+                .collect::<Vec<i32>>();
+            datasets.push(BpmData {
+                bpmnum: bpmnum - 1,
+                x: x_vals,
+                y: y_vals,
+                scaled: None,
+            });
+        }
+
+        Acquisition {
+            bpms: datasets,
+            ..self.header()
+        }
+    }
+
+    /// Packs an acquisition back into the layout returned by the archiver.
+    pub fn from_acquisition(acq: &Acquisition) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        if acq.bpms.is_empty() {
+            return Err(invalid("There is no data to pack"));
+        }
+        if acq.units != Units::Raw || acq.calibrated || !acq.calibration.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Raw data can only be written in the archiver's own units",
+            ));
+        }
+        if acq.fs <= 0.0 {
+            return Err(invalid(
+                "At least two samples are needed to find the sample rate",
+            ));
+        }
+        let n = acq.num_samples();
+        if acq
+            .bpms
+            .iter()
+            .any(|bpm| bpm.x.len() < n || bpm.y.len() < n)
+        {
+            return Err(invalid("All BPMs must have a position for every sample"));
+        }
+
+        let mut payload = Vec::with_capacity(n * acq.bpms.len() * 2 * DATSIZE);
+        for i in 0..n {
+            for bpm in &acq.bpms {
+                payload.extend_from_slice(&bpm.x[i].to_le_bytes());
+                payload.extend_from_slice(&bpm.y[i].to_le_bytes());
+            }
+        }
+
+        Ok(Self {
+            ring: acq.ring.clone(),
+            bpm_range: acq.bpms.iter().map(|bpm| bpm.bpmnum + 1).collect(),
+            fs: acq.fs,
+            decimation: acq.decimation,
+            start_time: acq.ts.time(0),
+            request: acq.request.clone(),
+            payload,
+        })
+    }
+
+    /// Writes the payload to `{basename}.bin` and the description of it to `{basename}.json`.
+    ///
+    /// Only the payload is compressed, so that the sidecar remains readable by anyone.  When
+    /// writing to stdout, the sidecar is written to stderr instead, or logged when using the JSON
+    /// log format.
+    pub fn write_to_files(&self, basename: &str, compression: &Compression) -> Result<()> {
+        let data_fname = output_name(basename, ".bin");
+        let sidecar_fname = output_name(basename, ".json");
+        check_overwrite(&[
+            output_name(&data_fname, compression.extension()),
+            sidecar_fname.clone(),
+        ])?;
+
+        let mut file = compression.create(&data_fname)?;
+        file.write_all(&self.payload)?;
+        file.finish()?;
+        let data_fname = output_name(&data_fname, compression.extension());
+
+        let sidecar = RawSidecar {
+            data_file: Path::new(&data_fname)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
+            ring: self.ring.to_string(),
+            bpms: self.bpm_range.clone(),
+            bpm_names: self
+                .bpm_range
+                .iter()
+                .map(|x| get_bpm_name(x - 1, &self.ring).unwrap())
+                .collect(),
+            fs: self.fs,
+            decimation: self.decimation,
+            start_time: self.start_time,
+            num_samples: self.num_samples(),
+            layout: "interleaved little-endian i32 [x, y] per BPM per sample".to_string(),
+            request: self.request.clone(),
+        };
+        if basename == STDOUT && json_logs() {
+            // The JSON log has one object per line, so the sidecar is given as a field.
+            print_log_event(
+                "info",
+                "Raw data sidecar",
+                vec![("sidecar", serde_json::to_value(&sidecar)?)],
+            );
+        } else if basename == STDOUT {
+            serde_json::to_writer_pretty(stderr(), &sidecar)?;
+            writeln!(stderr())?;
+        } else {
+            let mut file = Compression::None.create(&sidecar_fname)?;
+            serde_json::to_writer_pretty(&mut file, &sidecar)?;
+            file.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Loads a raw dump given the path to its JSON sidecar.
+    pub fn read_from_sidecar(sidecar_fname: &str) -> Result<Self> {
+        let sidecar: RawSidecar = serde_json::from_reader(compress::open(sidecar_fname)?)?;
+
+        let ring = Ring::from_name(&sidecar.ring).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown ring '{}' in {}", sidecar.ring, sidecar_fname),
+            )
+        })?;
+
+        check_time_range(sidecar.start_time)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", sidecar_fname, e)))?;
+
+        let data_path = Path::new(sidecar_fname)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&sidecar.data_file);
+        let payload = compress::read_to_end(&data_path.to_string_lossy())?;
+
+        Ok(Self {
+            ring,
+            bpm_range: sidecar.bpms,
+            fs: sidecar.fs,
+            decimation: sidecar.decimation,
+            start_time: sidecar.start_time,
+            request: sidecar.request,
+            payload,
+        })
+    }
+}
+
+/// Writes the raw archiver payload and its sidecar, without formatting anything as text.
+pub struct RawWriter;
+
+impl OutputWriter for RawWriter {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn write_acquisition(
+        &self,
+        acq: &Acquisition,
+        fname: &str,
+        compression: &Compression,
+    ) -> Result<()> {
+        let basename = fname.strip_suffix(".bin").unwrap_or(fname);
+        RawData::from_acquisition(acq)?.write_to_files(basename, compression)
+    }
+
+    fn output_files(&self, fname: &str, compression: &Compression) -> Vec<String> {
+        let basename = fname.strip_suffix(".bin").unwrap_or(fname);
+        vec![
+            format!("{}.bin{}", basename, compression.extension()),
+            format!("{}.json", basename),
+        ]
+    }
+
+    fn write_raw(
+        &self,
+        raw: &RawData,
+        basename: &str,
+        compression: &Compression,
+    ) -> Result<Vec<WrittenFile>> {
+        let written = |fname: &str| {
+            if basename == STDOUT {
+                return vec![];
+            }
+            let bpms: Vec<String> = raw
+                .bpm_range
+                .iter()
+                .map(|x| get_bpm_name(x - 1, &raw.ring).unwrap())
+                .collect();
+            self.output_files(fname, compression)
+                .into_iter()
+                .map(|path| WrittenFile {
+                    path,
+                    bpms: bpms.clone(),
+                    samples: raw.num_samples(),
+                })
+                .collect()
+        };
+        if !is_template(basename) {
+            raw.write_to_files(basename, compression)?;
+            return Ok(written(basename));
+        }
+        let fname = |event: usize| {
+            output_file_name(
+                basename,
+                &TemplateFields {
+                    ring: &raw.ring,
+                    start: raw.start_time,
+                    bpmnum: None,
+                    extension: self.extension(),
+                    event,
+                },
+            )
+        };
+        let fname = fname(first_free_event(basename, fname, compression.extension())?)?;
+        let basename = fname.strip_suffix(".bin").unwrap_or(&fname);
+        raw.write_to_files(basename, compression)?;
+        Ok(written(basename))
+    }
+}
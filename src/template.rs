@@ -0,0 +1,86 @@
+use crate::bpmdata::{get_bpm_cell, get_bpm_name, Ring};
+use chrono::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Whether an output name is a template, such as `{date}/{ring}/{bpm_name}.{ext}`, rather than a
+/// plain basename.
+pub fn is_template(output: &str) -> bool {
+    output.contains('{')
+}
+
+/// The values that can be substituted into an output template.
+pub struct TemplateFields<'a> {
+    pub ring: &'a Ring,
+    pub start: DateTime<FixedOffset>,
+    /// The zero-based number of the BPM, for files holding a single BPM.
+    pub bpmnum: Option<usize>,
+    pub extension: &'a str,
+    pub event: usize,
+}
+
+/// Fills in the placeholders of an output template.
+///
+/// The placeholders are `{ring}`, `{bpmnum}`, `{bpm_name}`, `{cell}`, `{date}`, `{time}`,
+/// `{start}`, `{event}` and `{ext}`.  The `/` characters in BPM names are replaced by `_`, so that
+/// they do not create directories.  If the template has no `{ext}`, the extension is appended.
+pub fn expand(template: &str, fields: &TemplateFields) -> Result<String> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+    let mut output = String::with_capacity(template.len() * 2);
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        output.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| invalid(format!("Unclosed placeholder in '{}'", template)))?
+            + open;
+        let name = &rest[open + 1..close];
+        let bpm = |name: &str| {
+            fields
+                .bpmnum
+                .ok_or_else(|| invalid(format!("{{{}}} can only be used for per-BPM files", name)))
+        };
+        let value = match name {
+            "ring" => fields.ring.to_string(),
+            "bpmnum" => format!("{:03}", bpm(name)?),
+            "bpm_name" => get_bpm_name(bpm(name)?, fields.ring)
+                .unwrap_or_default()
+                .replace('/', "_"),
+            "cell" => get_bpm_name(bpm(name)?, fields.ring)
+                .and_then(|x| get_bpm_cell(&x))
+                .unwrap_or_default(),
+            "date" => fields.start.format("%Y-%m-%d").to_string(),
+            "time" => fields.start.format("%H%M%S").to_string(),
+            "start" => fields.start.format("%Y-%m-%dT%H%M%S%.3f").to_string(),
+            "event" => fields.event.to_string(),
+            "ext" => fields.extension.to_string(),
+            _ => return Err(invalid(format!("Unknown placeholder '{{{}}}'", name))),
+        };
+        output.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    output.push_str(rest);
+    if !template.contains("{ext}") {
+        output = format!("{}.{}", output, fields.extension);
+    }
+    Ok(output)
+}
+
+/// Expands a template, choosing the `{event}` index so as not to overwrite earlier output.
+///
+/// The index starts at zero and is increased until the file given by `first` does not exist.
+/// Templates without `{event}` are expanded with an index of zero.
+pub fn first_free_event(
+    template: &str,
+    first: impl Fn(usize) -> Result<String>,
+    compression_extension: &str,
+) -> Result<usize> {
+    if !template.contains("{event}") {
+        return Ok(0);
+    }
+    let mut event = 0;
+    while Path::new(&format!("{}{}", first(event)?, compression_extension)).exists() {
+        event += 1;
+    }
+    Ok(event)
+}
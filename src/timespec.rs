@@ -0,0 +1,293 @@
+use crate::timezone::{self, from_local, from_utc, in_timezone};
+use chrono::prelude::*;
+use chrono::Duration;
+
+const LOCAL_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+const TIME_OF_DAY_FORMATS: &[&str] = &["%H:%M:%S%.f", "%H:%M"];
+
+/// Parses a time given on the command line.
+///
+/// The accepted forms are
+/// - `2023-10-04T12:00:00.000`, with or without the seconds and with a `T` or a space, in the
+///   time zone given by `--tz`,
+/// - RFC 3339 times with an offset, such as `2023-10-04T10:00:00Z` or
+///   `2023-10-04T12:00:00+02:00`,
+/// - `2023-10-04`, for midnight at the start of the day,
+/// - Unix epoch seconds, such as `1696413600` or `1696413600.25`,
+/// - `now`, or a time relative to it, such as `now-10m` or `now-1h30m`,
+/// - `14:00`, `today 14:00`, `yesterday` and `yesterday 14:00`.
+pub fn parse_time(arg: &str) -> Result<DateTime<FixedOffset>, String> {
+    parse_time_at(arg.trim(), timezone::now()).and_then(check_time_range)
+}
+
+/// Fails unless a time can be given in nanoseconds since the Unix epoch, as the archiver and the
+/// time axes of the data need.
+pub fn check_time_range(time: DateTime<FixedOffset>) -> Result<DateTime<FixedOffset>, String> {
+    match time.timestamp_nanos_opt() {
+        Some(_) => Ok(time),
+        None => Err(format!(
+            "{} is outside the range of times that can be handled, from 1677 to 2262",
+            time
+        )),
+    }
+}
+
+fn parse_time_at(arg: &str, now: DateTime<FixedOffset>) -> Result<DateTime<FixedOffset>, String> {
+    let lower = arg.to_lowercase();
+
+    if let Some(offset) = lower.strip_prefix("now") {
+        let offset = offset.trim_start();
+        return match offset.chars().next() {
+            None => Ok(now),
+            Some('-') => Ok(in_timezone(now - parse_duration(&offset[1..])?)),
+            Some('+') => Ok(in_timezone(now + parse_duration(&offset[1..])?)),
+            Some(_) => Err(format!("expected now, now-10m or now+10m, not '{}'", arg)),
+        };
+    }
+
+    for (word, days_ago) in [("today", 0), ("yesterday", 1)] {
+        if let Some(time) = lower.strip_prefix(word) {
+            let date = now.date_naive() - Duration::days(days_ago);
+            return match time.trim() {
+                "" => from_local(date.and_time(NaiveTime::MIN)),
+                time => from_local(date.and_time(parse_time_of_day(time)?)),
+            };
+        }
+    }
+
+    if let Ok(time) = parse_time_of_day(arg) {
+        return from_local(now.date_naive().and_time(time));
+    }
+
+    if arg.chars().all(|c| c.is_ascii_digit() || c == '.') && !arg.is_empty() {
+        return parse_epoch(arg);
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(arg) {
+        return Ok(in_timezone(time));
+    }
+
+    for format in LOCAL_FORMATS {
+        if let Ok(time) = NaiveDateTime::parse_from_str(arg, format) {
+            return from_local(time);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        return from_local(date.and_time(NaiveTime::MIN));
+    }
+
+    Err(format!(
+        "could not understand the time '{}'; expected e.g. 2023-10-04T12:00:00, \
+         2023-10-04T10:00:00Z, 1696413600, now-10m or 'yesterday 14:00'",
+        arg
+    ))
+}
+
+/// Parses a length of time, such as `30s`, `10m`, `1h30m`, `2d` or `250ms`.  A number on its own
+/// is taken to be in seconds.
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "expected a duration such as 30s, 10m, 1h30m or 250ms, not '{}'",
+            arg
+        )
+    };
+    let mut rest = arg.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut nanoseconds = 0.0;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().map_err(|_| invalid())?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit_ns = match &rest[..unit_len] {
+            "ms" => 1e6,
+            "" | "s" => 1e9,
+            "m" | "min" => 60e9,
+            "h" => 3600e9,
+            "d" => 86400e9,
+            _ => return Err(invalid()),
+        };
+        nanoseconds += number * unit_ns;
+        rest = &rest[unit_len..];
+    }
+    if nanoseconds > i64::MAX as f64 {
+        return Err(format!(
+            "the duration '{}' is too long; it must be less than 292 years",
+            arg
+        ));
+    }
+    Ok(Duration::nanoseconds(nanoseconds.round() as i64))
+}
+
+fn parse_time_of_day(arg: &str) -> Result<NaiveTime, String> {
+    TIME_OF_DAY_FORMATS
+        .iter()
+        .find_map(|format| NaiveTime::parse_from_str(arg, format).ok())
+        .ok_or_else(|| format!("expected a time of day such as 14:00, not '{}'", arg))
+}
+
+fn parse_epoch(arg: &str) -> Result<DateTime<FixedOffset>, String> {
+    let invalid = || format!("could not understand the Unix time '{}'", arg);
+    let (seconds, fraction) = arg.split_once('.').unwrap_or((arg, ""));
+    if fraction.len() > 9 {
+        return Err(invalid());
+    }
+    let seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+    let nanoseconds: u32 = format!("{:0<9}", fraction).parse().map_err(|_| invalid())?;
+    DateTime::from_timestamp(seconds, nanoseconds)
+        .map(from_utc)
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timezone::{parse_timezone, set_timezone};
+
+    /// Parses a time relative to a fixed `now`, in a time zone with daylight saving time.
+    fn parse(arg: &str, now: &str) -> Result<DateTime<FixedOffset>, String> {
+        set_timezone(Some(parse_timezone("Europe/Stockholm").unwrap()));
+        parse_time_at(arg, DateTime::parse_from_rfc3339(now).unwrap())
+    }
+
+    fn time(arg: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(arg).unwrap()
+    }
+
+    const NOW: &str = "2023-10-04T12:00:00+02:00";
+
+    #[test]
+    fn relative_times() {
+        assert_eq!(parse("now", NOW), Ok(time(NOW)));
+        assert_eq!(parse("now-10m", NOW), Ok(time("2023-10-04T11:50:00+02:00")));
+        assert_eq!(
+            parse("NOW + 1h30m", NOW),
+            Ok(time("2023-10-04T13:30:00+02:00"))
+        );
+        assert!(parse("now*2", NOW).is_err());
+    }
+
+    #[test]
+    fn relative_times_across_a_clock_change() {
+        // The clocks went back from 03:00 to 02:00 on 2023-10-29.
+        let result = parse("now-2h", "2023-10-29T03:30:00+01:00").unwrap();
+        assert_eq!(result, time("2023-10-29T02:30:00+02:00"));
+        assert_eq!(result.offset().local_minus_utc(), 2 * 3600);
+    }
+
+    #[test]
+    fn epoch_times() {
+        assert_eq!(parse("1696413600", NOW), Ok(time("2023-10-04T10:00:00Z")));
+        assert_eq!(
+            parse("1696413600.25", NOW),
+            Ok(time("2023-10-04T10:00:00.25Z"))
+        );
+        assert!(parse("1696413600.0000000001", NOW).is_err());
+    }
+
+    #[test]
+    fn rfc3339_times_are_expressed_in_the_time_zone() {
+        let result = parse("2023-10-04T10:00:00Z", NOW).unwrap();
+        assert_eq!(result, time("2023-10-04T12:00:00+02:00"));
+        assert_eq!(result.offset().local_minus_utc(), 2 * 3600);
+    }
+
+    #[test]
+    fn local_times() {
+        let expected = Ok(time("2023-10-04T12:00:00+02:00"));
+        assert_eq!(parse("2023-10-04T12:00:00.000", NOW), expected);
+        assert_eq!(parse("2023-10-04 12:00", NOW), expected);
+        assert_eq!(
+            parse("2023-10-04", NOW),
+            Ok(time("2023-10-04T00:00:00+02:00"))
+        );
+        assert_eq!(
+            parse("2023-12-04", NOW),
+            Ok(time("2023-12-04T00:00:00+01:00"))
+        );
+        assert!(parse("2023-10-04 25:00", NOW).is_err());
+    }
+
+    #[test]
+    fn named_days_and_times_of_day() {
+        assert_eq!(parse("14:00", NOW), Ok(time("2023-10-04T14:00:00+02:00")));
+        assert_eq!(parse("today", NOW), Ok(time("2023-10-04T00:00:00+02:00")));
+        assert_eq!(
+            parse("today 09:30:15", NOW),
+            Ok(time("2023-10-04T09:30:15+02:00"))
+        );
+        assert_eq!(
+            parse("yesterday", NOW),
+            Ok(time("2023-10-03T00:00:00+02:00"))
+        );
+        assert_eq!(
+            parse("Yesterday 14:00", NOW),
+            Ok(time("2023-10-03T14:00:00+02:00"))
+        );
+        assert!(parse("yesterday noon", NOW).is_err());
+    }
+
+    #[test]
+    fn times_repeated_or_skipped_by_clock_changes_are_refused() {
+        let error = parse("2023-10-29 02:30", NOW).unwrap_err();
+        assert!(error.contains("happens twice"), "{}", error);
+        assert!(error.contains("2023-10-29T02:30:00+02:00"), "{}", error);
+        assert!(error.contains("2023-10-29T02:30:00+01:00"), "{}", error);
+        let error = parse("2023-03-26 02:30", NOW).unwrap_err();
+        assert!(error.contains("does not exist"), "{}", error);
+        // With an offset, the time is not ambiguous.
+        assert_eq!(
+            parse("2023-10-29T02:30:00+01:00", NOW),
+            Ok(time("2023-10-29T02:30:00+01:00"))
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::seconds(30)));
+        assert_eq!(parse_duration("90"), Ok(Duration::seconds(90)));
+        assert_eq!(parse_duration("10min"), Ok(Duration::minutes(10)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::days(2)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::milliseconds(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::milliseconds(1500)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn times_outside_the_nanosecond_range_are_refused() {
+        set_timezone(Some(parse_timezone("Europe/Stockholm").unwrap()));
+        assert!(parse_time("99999999999").is_err());
+        assert!(parse_time("2300-01-01").is_err());
+        assert!(parse_time("1600-01-01T00:00:00Z").is_err());
+        assert!(parse_time("2262-01-01").is_ok());
+        assert!(parse_time("1700-01-01").is_ok());
+    }
+
+    #[test]
+    fn durations_too_long_to_represent_are_refused() {
+        assert!(parse_duration("100000000d").is_err());
+        assert!(parse_duration("106000d").is_ok());
+    }
+
+    #[test]
+    fn unrecognised_times() {
+        assert!(parse("", NOW).is_err());
+        assert!(parse("next tuesday", NOW).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::prelude::*;
+use chrono::LocalResult;
+use chrono_tz::{Tz, TZ_VARIANTS};
+use std::sync::OnceLock;
+
+/// The time zone given by `--tz`.  If it is not set, the local time zone of the machine is used.
+static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+pub fn set_timezone(tz: Option<Tz>) {
+    if let Some(tz) = tz {
+        let _ = TIMEZONE.set(tz);
+    }
+}
+
+/// Finds a time zone by its IANA name, ignoring case.
+pub fn parse_timezone(arg: &str) -> Result<Tz, String> {
+    TZ_VARIANTS
+        .iter()
+        .find(|tz| tz.name().eq_ignore_ascii_case(arg))
+        .copied()
+        .ok_or_else(|| {
+            format!(
+                "unknown time zone '{}'; expected e.g. UTC or Europe/Stockholm",
+                arg
+            )
+        })
+}
+
+/// The name of the time zone in use, for messages.
+pub fn timezone_name() -> String {
+    match TIMEZONE.get() {
+        Some(tz) => tz.name().to_string(),
+        None => "the local time zone".to_string(),
+    }
+}
+
+/// Expresses a moment in the time zone in use, with the offset that applies at that moment.
+pub fn from_utc(time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    match TIMEZONE.get() {
+        Some(tz) => time.with_timezone(tz).fixed_offset(),
+        None => time.with_timezone(&Local).fixed_offset(),
+    }
+}
+
+pub fn from_nanos(ns: i64) -> DateTime<FixedOffset> {
+    from_utc(DateTime::from_timestamp_nanos(ns))
+}
+
+pub fn now() -> DateTime<FixedOffset> {
+    from_utc(Utc::now())
+}
+
+/// Corrects the offset of a time found by adding a duration to another, which may be wrong if
+/// the clocks changed in between.
+pub fn in_timezone(time: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    from_utc(time.to_utc())
+}
+
+/// Interprets a date and time without an offset in the time zone in use.
+///
+/// Times that are skipped or repeated when the clocks change are refused, rather than guessed.
+pub fn from_local(time: NaiveDateTime) -> Result<DateTime<FixedOffset>, String> {
+    match local_result(time) {
+        LocalResult::Single(time) => Ok(time),
+        LocalResult::Ambiguous(first, second) => Err(format!(
+            "{} happens twice in {} as the clocks go back, so give the offset explicitly: \
+             either {} or {}",
+            time,
+            timezone_name(),
+            first.to_rfc3339(),
+            second.to_rfc3339()
+        )),
+        LocalResult::None => Err(skipped(time)),
+    }
+}
+
+/// Interprets a date and time without an offset, as written to files by earlier versions, in
+/// the time zone in use.
+///
+/// Unlike `from_local`, times repeated when the clocks go back are not refused, as the files
+/// were written without a choice.  The first of the two is taken, unless it is before
+/// `not_before`, so that the times of a file keep increasing through the repeated hour.
+pub fn from_local_in_file(
+    time: NaiveDateTime,
+    not_before: Option<DateTime<FixedOffset>>,
+) -> Result<DateTime<FixedOffset>, String> {
+    match local_result(time) {
+        LocalResult::Single(time) => Ok(time),
+        LocalResult::Ambiguous(first, second) if not_before.is_some_and(|x| first < x) => {
+            Ok(second)
+        }
+        LocalResult::Ambiguous(first, _) => Ok(first),
+        LocalResult::None => Err(skipped(time)),
+    }
+}
+
+fn local_result(time: NaiveDateTime) -> LocalResult<DateTime<FixedOffset>> {
+    match TIMEZONE.get() {
+        Some(tz) => time.and_local_timezone(*tz).map(|x| x.fixed_offset()),
+        None => time.and_local_timezone(Local).map(|x| x.fixed_offset()),
+    }
+}
+
+fn skipped(time: NaiveDateTime) -> String {
+    format!(
+        "{} does not exist in {} as the clocks go forward",
+        time,
+        timezone_name()
+    )
+}
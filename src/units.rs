@@ -0,0 +1,253 @@
+use crate::bpmdata::get_bpm_from_name;
+use crate::compress;
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Result};
+
+/// The units that positions are written in.
+///
+/// The archiver returns positions as integers in nanometres, which are written unchanged by
+/// default.  The other units are written as floats.
+#[derive(Default, PartialEq, Debug, Clone, Copy)]
+pub enum Units {
+    #[default]
+    Raw,
+    Nm,
+    Um,
+    Mm,
+}
+
+impl Units {
+    pub fn from_string(arg: &str) -> Option<Self> {
+        match arg.to_lowercase().as_str() {
+            "raw" => Some(Self::Raw),
+            "nm" => Some(Self::Nm),
+            "um" | "µm" => Some(Self::Um),
+            "mm" => Some(Self::Mm),
+            _ => None,
+        }
+    }
+
+    /// The name of the units as recorded in the output files.  Raw values have no name.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Self::Raw => None,
+            Self::Nm => Some("nm"),
+            Self::Um => Some("um"),
+            Self::Mm => Some("mm"),
+        }
+    }
+
+    fn nm_per_unit(self) -> f64 {
+        match self {
+            Self::Raw | Self::Nm => 1.0,
+            Self::Um => 1e3,
+            Self::Mm => 1e6,
+        }
+    }
+
+    /// Converts a position in these units back to the archiver's units.
+    pub fn to_raw(self, value: f64) -> i32 {
+        (value * self.nm_per_unit()).round() as i32
+    }
+
+    /// Parses a position written in these units.  Raw positions must be integers.
+    pub fn parse(self, value: &str) -> Option<f64> {
+        match self {
+            Self::Raw => value.parse::<i32>().ok().map(f64::from),
+            _ => value.parse().ok(),
+        }
+    }
+}
+
+/// The gain and offset (in nm) applied to the raw positions of one BPM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Correction {
+    pub x_gain: f64,
+    pub x_offset: f64,
+    pub y_gain: f64,
+    pub y_offset: f64,
+}
+
+impl Default for Correction {
+    fn default() -> Self {
+        Self {
+            x_gain: 1.0,
+            x_offset: 0.0,
+            y_gain: 1.0,
+            y_offset: 0.0,
+        }
+    }
+}
+
+/// Per-BPM corrections for BPMs with known calibration errors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    corrections: HashMap<String, Correction>,
+}
+
+impl Calibration {
+    /// Reads a calibration file.
+    ///
+    /// Each line holds a BPM name followed by its x gain, x offset, y gain and y offset,
+    /// separated by whitespace or commas.  Offsets are in nm, and blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn read_from_file(fname: &str) -> Result<Self> {
+        let invalid = |line: usize, msg: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}: {}", fname, line + 1, msg),
+            )
+        };
+        let mut corrections = HashMap::new();
+        for (i, line) in compress::open(fname)?.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|x| !x.is_empty())
+                .collect();
+            let values: Vec<f64> = match fields.as_slice() {
+                [_, values @ ..] if values.len() == 4 => values
+                    .iter()
+                    .map(|x| x.parse().ok())
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid(i, "Could not parse a gain or offset"))?,
+                _ => {
+                    return Err(invalid(
+                        i,
+                        "Expected a line of the form 'bpm x_gain x_offset y_gain y_offset'",
+                    ))
+                }
+            };
+            if get_bpm_from_name(fields[0]).is_none() {
+                return Err(invalid(i, format!("Unknown BPM '{}'", fields[0]).as_str()));
+            }
+            corrections.insert(
+                fields[0].to_string(),
+                Correction {
+                    x_gain: values[0],
+                    x_offset: values[1],
+                    y_gain: values[2],
+                    y_offset: values[3],
+                },
+            );
+        }
+        Ok(Self { corrections })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.corrections.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.corrections.contains_key(name)
+    }
+
+    /// The correction for a BPM, which does nothing if the BPM is not in the calibration.
+    pub fn get(&self, name: &str) -> Correction {
+        self.corrections.get(name).copied().unwrap_or_default()
+    }
+}
+
+/// A single position, as written to the output files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Raw(i32),
+    Scaled(f64),
+}
+
+impl Position {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Self::Raw(val) => *val as f64,
+            Self::Scaled(val) => *val,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raw(val) => write!(f, "{}", val),
+            Self::Scaled(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+impl Serialize for Position {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::Raw(val) => serializer.serialize_i32(*val),
+            Self::Scaled(val) => serializer.serialize_f64(*val),
+        }
+    }
+}
+
+/// The positions of one BPM along one axis, in the units they are to be written in.
+pub enum Positions<'a> {
+    Raw(&'a [i32]),
+    Scaled(Vec<f64>),
+}
+
+/// Positions as read from a file written in physical units.
+///
+/// These are kept as they were written so that converting such a file again does not round
+/// them to whole nanometres.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaledPositions {
+    pub units: Units,
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+}
+
+impl Positions<'_> {
+    pub fn new<'a>(raw: &'a [i32], units: Units, gain: f64, offset: f64) -> Positions<'a> {
+        if units == Units::Raw {
+            return Positions::Raw(raw);
+        }
+        Positions::Scaled(
+            raw.iter()
+                .map(|x| (*x as f64 * gain + offset) / units.nm_per_unit())
+                .collect(),
+        )
+    }
+
+    /// Converts positions read in the units `from` to `units`, applying a gain and offset (in
+    /// nm) on the way.
+    pub fn from_scaled<'a>(
+        scaled: &[f64],
+        from: Units,
+        units: Units,
+        gain: f64,
+        offset: f64,
+    ) -> Positions<'a> {
+        if from == units && gain == 1.0 && offset == 0.0 {
+            return Positions::Scaled(scaled.to_vec());
+        }
+        Positions::Scaled(
+            scaled
+                .iter()
+                .map(|x| (x * from.nm_per_unit() * gain + offset) / units.nm_per_unit())
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, i: usize) -> Position {
+        match self {
+            Self::Raw(vals) => Position::Raw(vals[i]),
+            Self::Scaled(vals) => Position::Scaled(vals[i]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Raw(vals) => vals.len(),
+            Self::Scaled(vals) => vals.len(),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::acquisition::Acquisition;
+use crate::bpmdata::{BpmData, Ring};
+use crate::compress::{
+    check_overwrite, output_name, overwrite, would_clobber, Compression, Overwrite, STDOUT,
+};
+use crate::logging::{print_log_message, print_log_warning};
+use crate::rawdata::RawData;
+use crate::template::{expand, first_free_event, is_template, TemplateFields};
+use chrono::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::thread;
+
+const NUM_WRITE_THREADS: usize = 7;
+
+/// A way of writing acquired data to disk.
+///
+/// Writers either produce one file per BPM (`per_bpm` returns true, and `write_bpm` is
+/// implemented) or a single file for the whole acquisition (`write_acquisition` is implemented).
+/// The naming of the files is handled by `write_output`.
+pub trait OutputWriter: Sync {
+    /// The extension of the files written, without the leading dot.
+    fn extension(&self) -> &'static str;
+
+    fn per_bpm(&self) -> bool {
+        false
+    }
+
+    fn write_bpm(
+        &self,
+        _acq: &Acquisition,
+        _bpm: &BpmData,
+        _fname: &str,
+        _compression: &Compression,
+    ) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This format cannot be written one BPM at a time",
+        ))
+    }
+
+    fn write_acquisition(
+        &self,
+        _acq: &Acquisition,
+        _fname: &str,
+        _compression: &Compression,
+    ) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "This format cannot be written for a whole acquisition",
+        ))
+    }
+
+    /// The files produced by `write_acquisition` for the given file name.
+    fn output_files(&self, fname: &str, compression: &Compression) -> Vec<String> {
+        vec![output_name(fname, compression.extension())]
+    }
+
+    /// Writes data exactly as it was returned by the archiver.
+    ///
+    /// By default the data is parsed and passed to `write_output`, but writers that can use the
+    /// payload directly should override this.
+    fn write_raw(
+        &self,
+        raw: &RawData,
+        basename: &str,
+        compression: &Compression,
+    ) -> Result<Vec<WrittenFile>> {
+        print_log_message("Parsing data");
+        write_output(self, &raw.to_acquisition(), basename, compression)
+    }
+}
+
+/// A file written by `write_output`, and what it holds.
+#[derive(Debug, Clone)]
+pub struct WrittenFile {
+    pub path: String,
+    pub bpms: Vec<String>,
+    pub samples: usize,
+}
+
+/// Writes an acquisition with the given writer, returning the files that were written.
+///
+/// Per-BPM writers produce files named `{basename}_{bpmnum:03}.{extension}`, written in
+/// parallel, and whole-acquisition writers produce a single file named
+/// `{basename}.{extension}`.  If the basename is a template (see `template::expand`), it is
+/// expanded for each file instead.
+///
+/// Unless `--force` was given, nothing is written if any of the files already exists.  With
+/// `--no-clobber`, the other files are still written, but an error is returned afterwards.
+pub fn write_output<W: OutputWriter + ?Sized>(
+    writer: &W,
+    acq: &Acquisition,
+    basename: &str,
+    compression: &Compression,
+) -> Result<Vec<WrittenFile>> {
+    let start = acq.start_time();
+    let fname = |bpmnum: Option<usize>, event: usize| {
+        output_file_name(
+            basename,
+            &TemplateFields {
+                ring: &acq.ring,
+                start,
+                bpmnum,
+                extension: writer.extension(),
+                event,
+            },
+        )
+    };
+
+    if !writer.per_bpm() {
+        let event = first_free_event(
+            basename,
+            |event| fname(None, event),
+            compression.extension(),
+        )?;
+        let fname = fname(None, event)?;
+        print_log_message(format!("Writing the acquisition to '{}'", fname).as_str());
+        writer.write_acquisition(acq, &fname, compression)?;
+        if basename == STDOUT {
+            return Ok(vec![]);
+        }
+        return Ok(writer
+            .output_files(&fname, compression)
+            .into_iter()
+            .map(|path| WrittenFile {
+                path,
+                bpms: acq.bpm_names(),
+                samples: acq.num_samples(),
+            })
+            .collect());
+    }
+
+    check_per_bpm_template(basename)?;
+    let first_bpm = acq.bpms.first().map(|bpm| bpm.bpmnum);
+    let event = first_free_event(
+        basename,
+        |event| fname(first_bpm, event),
+        compression.extension(),
+    )?;
+
+    let mut files = vec![];
+    let mut skipped = vec![];
+    for bpm in &acq.bpms {
+        let fname = fname(Some(bpm.bpmnum), event)?;
+        let dest = output_name(&fname, compression.extension());
+        if overwrite() == Overwrite::Skip && would_clobber(&dest) {
+            print_log_warning(format!("Not overwriting '{}'", dest).as_str());
+            skipped.push(dest);
+        } else {
+            files.push((bpm, fname));
+        }
+    }
+    check_overwrite(
+        &files
+            .iter()
+            .map(|(_, fname)| output_name(fname, compression.extension()))
+            .collect::<Vec<_>>(),
+    )?;
+
+    if basename == STDOUT {
+        print_log_message("Writing all BPMs to stdout.");
+        for (bpm, fname) in &files {
+            writer.write_bpm(acq, bpm, fname, compression)?;
+        }
+        return Ok(vec![]);
+    }
+
+    print_log_message("Starting file-writing threads.");
+    let chunk_size = files.len().div_ceil(NUM_WRITE_THREADS).max(1);
+    let results: Vec<Result<()>> = thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .try_for_each(|(bpm, fname)| writer.write_bpm(acq, bpm, fname, compression))
+                })
+            })
+            .collect();
+        print_log_message("Waiting for file-write threads to finish.");
+        handles.into_iter().map(|x| x.join().unwrap()).collect()
+    });
+    results.into_iter().collect::<Result<()>>()?;
+    if !skipped.is_empty() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "{} of the output files already existed and were left alone",
+                skipped.len()
+            ),
+        ));
+    }
+    Ok(files
+        .into_iter()
+        .map(|(bpm, fname)| WrittenFile {
+            path: output_name(&fname, compression.extension()),
+            bpms: vec![acq.bpm_name(bpm)],
+            samples: acq.num_samples(),
+        })
+        .collect())
+}
+
+/// The files that `write_output` would write for an acquisition of the given BPMs (numbered from
+/// zero), without writing anything.  Nothing is listed when writing to stdout.
+pub fn output_paths<W: OutputWriter + ?Sized>(
+    writer: &W,
+    ring: &Ring,
+    start: DateTime<FixedOffset>,
+    bpmnums: &[usize],
+    basename: &str,
+    compression: &Compression,
+) -> Result<Vec<String>> {
+    if basename == STDOUT {
+        return Ok(vec![]);
+    }
+    let fname = |bpmnum: Option<usize>, event: usize| {
+        output_file_name(
+            basename,
+            &TemplateFields {
+                ring,
+                start,
+                bpmnum,
+                extension: writer.extension(),
+                event,
+            },
+        )
+    };
+    if !writer.per_bpm() {
+        let event = first_free_event(
+            basename,
+            |event| fname(None, event),
+            compression.extension(),
+        )?;
+        return Ok(writer.output_files(&fname(None, event)?, compression));
+    }
+    check_per_bpm_template(basename)?;
+    let event = first_free_event(
+        basename,
+        |event| fname(bpmnums.first().copied(), event),
+        compression.extension(),
+    )?;
+    bpmnums
+        .iter()
+        .map(|bpmnum| {
+            Ok(output_name(
+                &fname(Some(*bpmnum), event)?,
+                compression.extension(),
+            ))
+        })
+        .collect()
+}
+
+fn check_per_bpm_template(basename: &str) -> Result<()> {
+    if is_template(basename) && !basename.contains("{bpmnum}") && !basename.contains("{bpm_name}") {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The output template must contain {bpmnum} or {bpm_name} to write one file per BPM",
+        ));
+    }
+    Ok(())
+}
+
+/// The name of one output file, from either a plain basename or a template.
+pub fn output_file_name(basename: &str, fields: &TemplateFields) -> Result<String> {
+    if is_template(basename) {
+        return expand(basename, fields);
+    }
+    Ok(match fields.bpmnum {
+        Some(bpmnum) => output_name(
+            basename,
+            format!("_{:03}.{}", bpmnum, fields.extension).as_str(),
+        ),
+        None => output_name(basename, format!(".{}", fields.extension).as_str()),
+    })
+}